@@ -9,6 +9,8 @@ const EXPIRY_INTERVAL: u64 = 600;
 pub struct UsersCache {
     ids: Arc<DashMap<String, (Instant, Option<String>)>>,
     logins: Arc<DashMap<String, (Instant, Option<String>)>>,
+    /// Ids of logins which are no longer in use, stored as None if no user had the login
+    previous_logins: Arc<DashMap<String, (Instant, Option<String>)>>,
 }
 
 impl UsersCache {
@@ -28,6 +30,10 @@ impl UsersCache {
         }
     }
 
+    pub fn insert_previous_login(&self, name: String, id: Option<String>) {
+        self.previous_logins.insert(name, (Instant::now(), id));
+    }
+
     pub fn get_login(&self, id: &str) -> Option<Option<String>> {
         if let Some(entry) = self.ids.get(id) {
             if entry.value().0.elapsed().as_secs() > EXPIRY_INTERVAL {
@@ -60,4 +66,20 @@ impl UsersCache {
             None
         }
     }
+
+    pub fn get_previous_login_id(&self, name: &str) -> Option<Option<String>> {
+        if let Some(entry) = self.previous_logins.get(name) {
+            if entry.value().0.elapsed().as_secs() > EXPIRY_INTERVAL {
+                drop(entry);
+                trace!("Removing previous login {name} from cache");
+                self.previous_logins.remove(name);
+                None
+            } else {
+                trace!("Using cached value for previous login {name}");
+                Some(entry.value().1.clone())
+            }
+        } else {
+            None
+        }
+    }
 }
@@ -1,11 +1,24 @@
 pub mod cache;
 
 use self::cache::UsersCache;
-use crate::{config::Config, db::delete_user_logs, error::Error, Result};
+use crate::{
+    bot::BotMessage,
+    config::Config,
+    db::{
+        delete_channel_logs, delete_late_channel_logs, delete_user_channel_logs, delete_user_logs,
+        read_pending_mutations, read_user_id_by_previous_login,
+    },
+    error::Error,
+    web::schema::MutationProgress,
+    Result,
+};
 use anyhow::Context;
-use dashmap::DashSet;
-use std::{collections::HashMap, sync::Arc};
-use tracing::{debug, info};
+use arc_swap::ArcSwap;
+use chrono::NaiveDate;
+use dashmap::{DashMap, DashSet};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tokio::sync::{mpsc::Sender, Mutex};
+use tracing::{debug, error, info};
 use twitch_api2::{helix::users::GetUsersRequest, twitch_oauth2::AppAccessToken, HelixClient};
 
 #[derive(Clone)]
@@ -15,7 +28,13 @@ pub struct App {
     pub users: UsersCache,
     pub optout_codes: Arc<DashSet<String>>,
     pub db: Arc<clickhouse::Client>,
-    pub config: Arc<Config>,
+    pub config: Arc<ArcSwap<Config>>,
+    /// Held while changing, saving or reloading the config, so concurrent changes are not lost
+    pub config_lock: Arc<Mutex<()>>,
+    /// Mutations started by purges in this process, by channel id
+    pub purge_mutations: Arc<DashMap<String, Vec<String>>>,
+    /// Channels whose logs are deleted again once their current purge has finished
+    pub repeating_purges: Arc<DashSet<String>>,
 }
 
 impl App {
@@ -84,7 +103,12 @@ impl App {
         }
         for name in names_to_request {
             if !users.values().any(|login| login == name.as_str()) {
-                self.users.insert_optional(None, Some(name.into_string()));
+                match self.read_user_id_by_previous_name(name.as_str()).await? {
+                    Some(id) => {
+                        users.insert(id, name.into_string());
+                    }
+                    None => self.users.insert_optional(None, Some(name.into_string())),
+                }
             }
         }
 
@@ -94,7 +118,7 @@ impl App {
     pub async fn get_user_id_by_name(&self, name: &str) -> Result<String> {
         match self.users.get_id(name) {
             Some(Some(id)) => Ok(id),
-            Some(None) => Err(Error::NotFound),
+            Some(None) => self.get_user_id_by_previous_name(name).await,
             None => {
                 let request = GetUsersRequest::builder().login(vec![name.into()]).build();
                 let response = self.helix_client.req_get(request, &*self.token).await?;
@@ -106,36 +130,216 @@ impl App {
                     }
                     None => {
                         self.users.insert_optional(None, Some(name.to_owned()));
-                        Err(Error::NotFound)
+                        self.get_user_id_by_previous_name(name).await
                     }
                 }
             }
         }
     }
 
-    pub async fn optout_user(&self, user_id: &str) -> anyhow::Result<()> {
-        delete_user_logs(&self.db, user_id)
-            .await
-            .context("Could not delete logs")?;
+    /// Fallback for logins which are no longer in use, e.g. after a rename
+    async fn get_user_id_by_previous_name(&self, name: &str) -> Result<String> {
+        self.read_user_id_by_previous_name(name)
+            .await?
+            .ok_or(Error::NotFound)
+    }
+
+    /// Cached including misses, so that unknown names do not cause a query every time
+    async fn read_user_id_by_previous_name(&self, name: &str) -> Result<Option<String>> {
+        let login = name.to_lowercase();
+
+        if let Some(user_id) = self.users.get_previous_login_id(&login) {
+            return Ok(user_id);
+        }
+
+        let user_id = read_user_id_by_previous_login(&self.db, &login).await?;
+        self.users.insert_previous_login(login, user_id.clone());
+        Ok(user_id)
+    }
+
+    /// Opts the user out of being logged, either everywhere or only in the given channel
+    pub async fn optout_user(&self, user_id: &str, channel_id: Option<&str>) -> anyhow::Result<()> {
+        match channel_id {
+            Some(channel_id) => {
+                delete_user_channel_logs(&self.db, channel_id, user_id)
+                    .await
+                    .context("Could not delete logs")?;
 
-        self.config.opt_out.insert(user_id.to_owned(), true);
-        self.config.save()?;
-        info!("User {user_id} opted out");
+                let _config_guard = self.config_lock.lock().await;
+                let config = self.config.load();
+                config
+                    .channel_opt_out
+                    .entry(user_id.to_owned())
+                    .or_default()
+                    .insert(channel_id.to_owned());
+                config.save()?;
+                info!("User {user_id} opted out in channel {channel_id}");
+            }
+            None => {
+                delete_user_logs(&self.db, user_id)
+                    .await
+                    .context("Could not delete logs")?;
+
+                let _config_guard = self.config_lock.lock().await;
+                let config = self.config.load();
+                config.opt_out.insert(user_id.to_owned(), true);
+                config.save()?;
+                info!("User {user_id} opted out");
+            }
+        }
 
         Ok(())
     }
 
+    /// Returns `false` if there was no such opt out
+    pub async fn remove_optout(
+        &self,
+        user_id: &str,
+        channel_id: Option<&str>,
+    ) -> anyhow::Result<bool> {
+        let _config_guard = self.config_lock.lock().await;
+        let config = self.config.load();
+
+        let removed = match channel_id {
+            Some(channel_id) => {
+                let removed = config
+                    .channel_opt_out
+                    .get_mut(user_id)
+                    .map_or(false, |mut channel_ids| channel_ids.remove(channel_id));
+                config
+                    .channel_opt_out
+                    .remove_if(user_id, |_, channel_ids| channel_ids.is_empty());
+                removed
+            }
+            None => config.opt_out.remove(user_id).is_some(),
+        };
+
+        if removed {
+            config.save()?;
+            info!("Removed opt out of user {user_id}");
+        }
+
+        Ok(removed)
+    }
+
+    pub fn is_user_opted_out(&self, channel_id: &str, user_id: &str) -> bool {
+        let config = self.config.load();
+
+        config.opt_out.contains_key(user_id)
+            || config
+                .channel_opt_out
+                .get(user_id)
+                .map_or(false, |channel_ids| channel_ids.contains(channel_id))
+    }
+
     pub fn check_opted_out(&self, channel_id: &str, user_id: Option<&str>) -> Result<()> {
-        if self.config.opt_out.contains_key(channel_id) {
+        if self.config.load().opt_out.contains_key(channel_id) {
             return Err(Error::OptedOut);
         }
 
         if let Some(user_id) = user_id {
-            if self.config.opt_out.contains_key(user_id) {
+            if self.is_user_opted_out(channel_id, user_id) {
                 return Err(Error::OptedOut);
             }
         }
 
         Ok(())
     }
+
+    /// Deletes logs of the channel between the given days (inclusive), or all of them if no days are given.
+    /// Deleting all logs is repeated in the background once the first deletion has finished.
+    pub async fn purge_channel_logs(
+        &self,
+        channel_id: &str,
+        from: Option<NaiveDate>,
+        to: Option<NaiveDate>,
+    ) -> Result<()> {
+        let mutation_keys = delete_channel_logs(&self.db, channel_id, from, to).await?;
+        self.purge_mutations
+            .insert(channel_id.to_owned(), mutation_keys.clone());
+
+        if from.is_none() && to.is_none() {
+            self.repeating_purges.insert(channel_id.to_owned());
+
+            let app = self.clone();
+            let channel_id = channel_id.to_owned();
+            tokio::spawn(async move {
+                let flush_interval =
+                    Duration::from_secs(app.config.load().clickhouse_flush_interval);
+
+                match delete_late_channel_logs(&app.db, &channel_id, &mutation_keys, flush_interval)
+                    .await
+                {
+                    Ok(late_mutation_keys) => {
+                        app.purge_mutations
+                            .entry(channel_id.clone())
+                            .or_default()
+                            .extend(late_mutation_keys);
+                    }
+                    Err(err) => {
+                        error!("Could not delete late logs of channel {channel_id}: {err}");
+                    }
+                }
+                app.repeating_purges.remove(&channel_id);
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Deletions of the channel's logs started by this process which have not finished yet,
+    /// and whether all logs will be deleted again afterwards
+    pub async fn read_purge_status(
+        &self,
+        channel_id: &str,
+    ) -> Result<(Vec<MutationProgress>, bool)> {
+        let mutation_keys = self
+            .purge_mutations
+            .get(channel_id)
+            .map(|keys| keys.clone())
+            .unwrap_or_default();
+        let mutations = read_pending_mutations(&self.db, &mutation_keys).await?;
+
+        Ok((mutations, self.repeating_purges.contains(channel_id)))
+    }
+
+    /// Reloads the config file, joining and parting channels which were added or removed in it
+    ///
+    /// The new config is only used once the changed channels have been looked up,
+    /// so a failed lookup keeps the old config in place
+    pub async fn reload_config(&self, bot_tx: &Sender<BotMessage>) -> anyhow::Result<()> {
+        let (joined, parted) = {
+            let _config_guard = self.config_lock.lock().await;
+
+            let new_config = Config::load()?;
+            let (joined, parted) = self.config.load().channel_changes(&new_config);
+
+            let joined: Vec<String> = self
+                .get_users(joined, vec![])
+                .await?
+                .into_values()
+                .collect();
+            let parted: Vec<String> = self
+                .get_users(parted, vec![])
+                .await?
+                .into_values()
+                .collect();
+
+            self.config.store(Arc::new(new_config));
+            info!("Reloaded config");
+
+            (joined, parted)
+        };
+
+        // The bot updates the config when joining or parting, so the lock must be released first
+        if !joined.is_empty() {
+            bot_tx.send(BotMessage::JoinChannels(joined)).await?;
+        }
+
+        if !parted.is_empty() {
+            bot_tx.send(BotMessage::PartChannels(parted)).await?;
+        }
+
+        Ok(())
+    }
 }
@@ -1,3 +1,5 @@
+use crate::migrator::{source::SourceFormat, CheckpointMode};
+use chrono::NaiveDate;
 use clap::{Parser, Subcommand};
 
 #[derive(Parser)]
@@ -9,16 +11,88 @@ pub struct Args {
 
 #[derive(Subcommand)]
 pub enum Command {
-    /// Migrate existing justlog logs
-    Migrate {
-        /// The justlog logs folder
-        #[clap(short, long, value_parser)]
-        source_dir: String,
-        /// List of channel ids to migrate (None specified = migrate all)
+    /// Migrate existing justlog logs or logs in other formats
+    Migrate(MigrateArgs),
+    /// Export logs into the justlog directory layout
+    Export {
+        /// The folder to write logs into
+        #[clap(short = 'o', long, value_parser)]
+        target_dir: String,
+        /// List of channel ids to export (None specified = export all)
         #[clap(short, long, value_parser)]
         channel_id: Vec<String>,
-        /// Parallel migration jobs
+        /// First day to export (YYYY-MM-DD)
+        #[clap(short, long, value_parser)]
+        from: NaiveDate,
+        /// Last day to export (YYYY-MM-DD), inclusive
+        #[clap(short, long, value_parser)]
+        to: NaiveDate,
+        /// Parallel export jobs
         #[clap(short, long, default_value_t = 1)]
         jobs: usize,
     },
+    /// Export the logs of a single user from all channels, along with their name history and statistics, into one NDJSON file
+    ExportUser {
+        /// Id of the user
+        #[clap(short, long, value_parser, required_unless_present = "user")]
+        user_id: Option<String>,
+        /// Login of the user, the most recent user who has been logged with it is exported
+        #[clap(long, value_parser, conflicts_with = "user_id")]
+        user: Option<String>,
+        /// The file to write the export into
+        #[clap(short, long, value_parser)]
+        output: String,
+    },
+    /// Delete logs of a channel
+    Purge {
+        /// Id of the channel to delete logs of
+        #[clap(short, long, value_parser)]
+        channel_id: String,
+        /// First day to delete (YYYY-MM-DD). If neither this nor `to` is set, all logs are deleted,
+        /// the channel is removed from the config and the running instance is reloaded with an
+        /// admin API key which has the `channels` scope
+        #[clap(short, long, value_parser)]
+        from: Option<NaiveDate>,
+        /// Last day to delete (YYYY-MM-DD), inclusive
+        #[clap(short, long, value_parser)]
+        to: Option<NaiveDate>,
+    },
+}
+
+#[derive(clap::Args)]
+pub struct MigrateArgs {
+    /// The logs folder
+    #[clap(short, long, value_parser)]
+    pub source_dir: String,
+    /// Format of the logs folder
+    #[clap(long, value_enum, default_value_t = SourceFormat::Justlog)]
+    pub format: SourceFormat,
+    /// List of channel ids to migrate (None specified = migrate all)
+    #[clap(short, long, value_parser)]
+    pub channel_id: Vec<String>,
+    /// Parallel migration jobs
+    #[clap(short, long, default_value_t = 1)]
+    pub jobs: usize,
+    /// Skip days which have already been migrated by a previous run (the default)
+    #[clap(long, conflicts_with = "force")]
+    pub resume: bool,
+    /// Migrate all days again, including the ones which have already been migrated by a previous run
+    #[clap(long)]
+    pub force: bool,
+    /// Parse all logs and report problems without writing anything
+    #[clap(long, conflicts_with_all = ["verify", "force"])]
+    pub dry_run: bool,
+    /// Compare the amount of lines in the logs with the rows in the database per day
+    #[clap(long, conflicts_with = "force")]
+    pub verify: bool,
+}
+
+impl MigrateArgs {
+    pub fn checkpoint_mode(&self) -> CheckpointMode {
+        // The flags conflict, so at most one of them is set
+        match (self.resume, self.force) {
+            (false, true) => CheckpointMode::Force,
+            _ => CheckpointMode::Resume,
+        }
+    }
 }
@@ -1,21 +1,38 @@
+mod token_storage;
+
+use self::token_storage::FileTokenStorage;
 use crate::{
     app::App,
+    config::Config,
     db::schema::Message,
-    logs::extract::{extract_channel_and_user_from_raw, extract_raw_timestamp},
+    logs::{
+        extract::{
+            extract_channel_and_user_from_raw, extract_login, extract_raw_timestamp,
+            MessageWithCommand, MessageWithTags,
+        },
+        schema::message::MessageType,
+    },
     ShutdownRx,
 };
-use anyhow::{anyhow, Context};
+use anyhow::{anyhow, bail, Context};
 use chrono::Utc;
 use lazy_static::lazy_static;
 use prometheus::{register_int_counter_vec, IntCounterVec};
 use std::{borrow::Cow, time::Duration};
 use tokio::{
-    sync::mpsc::{Receiver, Sender},
+    fs,
+    sync::{
+        broadcast,
+        mpsc::{Receiver, Sender},
+    },
     time::sleep,
 };
 use tracing::{debug, error, info, log::warn, trace};
+use twitch::Tag;
 use twitch_irc::{
-    login::LoginCredentials,
+    login::{
+        LoginCredentials, RefreshingLoginCredentials, StaticLoginCredentials, UserAccessToken,
+    },
     message::{AsRawIRC, IRCMessage, ServerMessage},
     ClientConfig, SecureTCPTransport, TwitchIRCClient,
 };
@@ -42,26 +59,88 @@ lazy_static! {
 
 const COMMAND_PREFIX: &str = "!rustlog ";
 
-pub async fn run<C: LoginCredentials>(
-    login_credentials: C,
+/// Fails if the bot is configured to use a token file which does not exist and cannot be created
+pub async fn check_credentials(config: &Config) -> anyhow::Result<()> {
+    if let Some(token_file) = &config.bot_token_file {
+        let has_initial_token =
+            config.bot_oauth_token.is_some() && config.bot_refresh_token.is_some();
+
+        if !has_initial_token && !fs::try_exists(token_file).await? {
+            bail!(
+                "Bot token file {token_file} does not exist, set botOauthToken and botRefreshToken to create it"
+            );
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn run(
     app: App,
     writer_tx: Sender<Message<'static>>,
+    live_tx: broadcast::Sender<Message<'static>>,
     shutdown_rx: ShutdownRx,
     command_rx: Receiver<BotMessage>,
 ) {
-    let bot = Bot::new(app, writer_tx);
-    bot.run(login_credentials, shutdown_rx, command_rx).await;
+    let config = app.config.load_full();
+    let bot = Bot::new(app, writer_tx, live_tx);
+
+    let oauth_token = config
+        .bot_oauth_token
+        .as_deref()
+        .map(|token| token.trim_start_matches("oauth:").to_owned());
+
+    if let Some(token_file) = &config.bot_token_file {
+        let initial_token = oauth_token.zip(config.bot_refresh_token.clone()).map(
+            |(access_token, refresh_token)| {
+                let now = Utc::now();
+                // Expire the seeded token immediately so it gets refreshed with a known expiry
+                UserAccessToken {
+                    access_token,
+                    refresh_token,
+                    created_at: now,
+                    expires_at: Some(now),
+                }
+            },
+        );
+
+        info!("Using refreshing login credentials stored in {token_file}");
+        let token_storage = FileTokenStorage::new(token_file, initial_token);
+        let login_credentials = RefreshingLoginCredentials::init(
+            config.client_id.clone(),
+            config.client_secret.clone(),
+            token_storage,
+        );
+        bot.run(login_credentials, shutdown_rx, command_rx).await;
+    } else if let Some((login, token)) = config.bot_login.clone().zip(oauth_token) {
+        info!("Logging in as {login}");
+        let login_credentials = StaticLoginCredentials::new(login, Some(token));
+        bot.run(login_credentials, shutdown_rx, command_rx).await;
+    } else {
+        info!("No bot credentials configured, connecting anonymously");
+        let login_credentials = StaticLoginCredentials::anonymous();
+        bot.run(login_credentials, shutdown_rx, command_rx).await;
+    }
 }
 
 #[derive(Clone)]
 struct Bot {
     app: App,
     writer_tx: Sender<Message<'static>>,
+    live_tx: broadcast::Sender<Message<'static>>,
 }
 
 impl Bot {
-    pub fn new(app: App, writer_tx: Sender<Message<'static>>) -> Bot {
-        Self { app, writer_tx }
+    pub fn new(
+        app: App,
+        writer_tx: Sender<Message<'static>>,
+        live_tx: broadcast::Sender<Message<'static>>,
+    ) -> Bot {
+        Self {
+            app,
+            writer_tx,
+            live_tx,
+        }
     }
 
     pub async fn run<C: LoginCredentials>(
@@ -77,7 +156,7 @@ impl Bot {
         let join_client = client.clone();
         tokio::spawn(async move {
             loop {
-                let channel_ids = app.config.channels.read().unwrap().clone();
+                let channel_ids = app.config.load().channels.read().unwrap().clone();
 
                 let interval = match app.get_users(Vec::from_iter(channel_ids), vec![]).await {
                     Ok(users) => {
@@ -173,6 +252,7 @@ impl Bot {
         if self
             .app
             .config
+            .load()
             .admins
             .iter()
             .any(|login| login == user_login)
@@ -200,21 +280,74 @@ impl Bot {
 
             let timestamp = extract_raw_timestamp(&irc_message)
                 .unwrap_or_else(|| Utc::now().timestamp_millis().try_into().unwrap());
-            let user_id = maybe_user_id.unwrap_or_default().to_owned();
-
-            if self.app.config.opt_out.contains_key(&user_id) {
-                return Ok(());
+            let mut user_id = maybe_user_id.unwrap_or_default().to_owned();
+
+            // CLEARMSG only carries the login of the user whose message was deleted
+            let mut unresolved_login = None;
+            if user_id.is_empty() && irc_message.message_type() == Some(MessageType::ClearMsg) {
+                if let Some(login) = extract_login(&irc_message) {
+                    match self.app.users.get_id(login) {
+                        Some(Some(id)) => user_id = id,
+                        _ => unresolved_login = Some(login.to_owned()),
+                    }
+                }
             }
 
-            let message = Message {
+            let mut message = Message {
                 channel_id: Cow::Owned(channel_id.to_owned()),
                 user_id: Cow::Owned(user_id),
                 timestamp,
                 raw: Cow::Owned(irc_message.as_raw_irc()),
+                message_type: irc_message
+                    .message_type()
+                    .map(|message_type| message_type as u8)
+                    .unwrap_or_default(),
+                login: Cow::Owned(extract_login(&irc_message).unwrap_or_default().to_owned()),
+                display_name: Cow::Owned(
+                    irc_message
+                        .get_tag(Tag::DisplayName)
+                        .unwrap_or_default()
+                        .to_owned(),
+                ),
+                message_id: Cow::Owned(irc_message.get_tag(Tag::Id).unwrap_or_default().to_owned()),
             };
-            self.writer_tx.send(message).await?;
+
+            match unresolved_login {
+                // Looking up the user can take a while, which must not hold up the other messages
+                Some(login) => {
+                    let bot = self.clone();
+                    tokio::spawn(async move {
+                        match bot.app.get_user_id_by_name(&login).await {
+                            Ok(id) => message.user_id = Cow::Owned(id),
+                            Err(err) => warn!("Could not get user id of {login}: {err}"),
+                        }
+
+                        if let Err(err) = bot.send_message(message).await {
+                            error!("Could not write message: {err}");
+                        }
+                    });
+                }
+                None => self.send_message(message).await?,
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn send_message(&self, message: Message<'static>) -> anyhow::Result<()> {
+        if self
+            .app
+            .is_user_opted_out(&message.channel_id, &message.user_id)
+        {
+            return Ok(());
         }
 
+        if self.live_tx.receiver_count() > 0 {
+            // Sending can only fail if all subscribers have disconnected in the meantime
+            let _ = self.live_tx.send(message.clone());
+        }
+        self.writer_tx.send(message).await?;
+
         Ok(())
     }
 
@@ -258,15 +391,30 @@ impl Bot {
         sender_id: &str,
     ) -> anyhow::Result<()> {
         let arg = args.first().context("No optout code provided")?;
+
+        // An optional `#channel` argument limits the opt out to that channel
+        let channel_id = match args.get(1) {
+            Some(channel) => Some(
+                self.app
+                    .get_user_id_by_name(channel.trim_start_matches('#'))
+                    .await?,
+            ),
+            None => None,
+        };
+
         if self.app.optout_codes.remove(*arg).is_some() {
-            self.app.optout_user(sender_id).await?;
+            self.app
+                .optout_user(sender_id, channel_id.as_deref())
+                .await?;
 
             Ok(())
         } else {
             if self.check_admin(sender_login).is_ok() {
                 let user_id = self.app.get_user_id_by_name(arg).await?;
 
-                self.app.optout_user(&user_id).await?;
+                self.app
+                    .optout_user(&user_id, channel_id.as_deref())
+                    .await?;
 
                 Ok(())
             } else {
@@ -290,8 +438,10 @@ impl Bot {
             .get_users(vec![], channels.iter().map(ToString::to_string).collect())
             .await?;
 
+        let _config_guard = self.app.config_lock.lock().await;
+        let config = self.app.config.load();
         {
-            let mut config_channels = self.app.config.channels.write().unwrap();
+            let mut config_channels = config.channels.write().unwrap();
 
             for (channel_id, channel_name) in channels {
                 match action {
@@ -309,7 +459,7 @@ impl Bot {
             }
         }
 
-        self.app.config.save()?;
+        config.save()?;
 
         Ok(())
     }
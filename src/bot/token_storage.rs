@@ -0,0 +1,71 @@
+use async_trait::async_trait;
+use std::{
+    fs::Permissions,
+    io::{self, ErrorKind},
+    os::unix::fs::PermissionsExt,
+    path::PathBuf,
+};
+use tokio::{
+    fs::{self, OpenOptions},
+    io::AsyncWriteExt,
+};
+use tracing::info;
+use twitch_irc::login::{TokenStorage, UserAccessToken};
+
+/// The token file contains credentials, so only the owner may read it
+const TOKEN_FILE_MODE: u32 = 0o600;
+
+/// Persists the bot's user access token as JSON so refreshed tokens survive restarts
+#[derive(Debug)]
+pub struct FileTokenStorage {
+    path: PathBuf,
+    /// Used when the token file does not exist yet
+    initial_token: Option<UserAccessToken>,
+}
+
+impl FileTokenStorage {
+    pub fn new(path: impl Into<PathBuf>, initial_token: Option<UserAccessToken>) -> Self {
+        Self {
+            path: path.into(),
+            initial_token,
+        }
+    }
+}
+
+#[async_trait]
+impl TokenStorage for FileTokenStorage {
+    type LoadError = io::Error;
+    type UpdateError = io::Error;
+
+    async fn load_token(&mut self) -> Result<UserAccessToken, Self::LoadError> {
+        match fs::read_to_string(&self.path).await {
+            Ok(contents) => serde_json::from_str(&contents)
+                .map_err(|err| io::Error::new(ErrorKind::InvalidData, err)),
+            Err(err) if err.kind() == ErrorKind::NotFound => {
+                let token = self.initial_token.take().ok_or(err)?;
+                info!("Seeding token file {:?} from config", self.path);
+                self.update_token(&token).await?;
+                Ok(token)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    async fn update_token(&mut self, token: &UserAccessToken) -> Result<(), Self::UpdateError> {
+        let json = serde_json::to_string_pretty(token)
+            .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(TOKEN_FILE_MODE)
+            .open(&self.path)
+            .await?;
+        // The mode is only applied to new files, so restrict files created by older versions as well
+        file.set_permissions(Permissions::from_mode(TOKEN_FILE_MODE))
+            .await?;
+        file.write_all(json.as_bytes()).await?;
+        file.flush().await
+    }
+}
@@ -1,11 +1,16 @@
-use anyhow::Context;
+use anyhow::{bail, Context};
 use dashmap::DashMap;
 use serde::{Deserialize, Serialize};
 use std::fs;
-use std::{collections::HashSet, sync::RwLock};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::RwLock,
+};
+use strum::AsRefStr;
 use tracing::info;
 
 const CONFIG_FILE_NAME: &str = "config.json";
+const LEGACY_ADMIN_KEY_NAME: &str = "adminAPIKey";
 
 #[derive(Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -16,24 +21,106 @@ pub struct Config {
     pub clickhouse_password: Option<String>,
     #[serde(default = "clickhouse_flush_interval")]
     pub clickhouse_flush_interval: u64,
+    #[serde(default)]
+    pub clickhouse_spool_dir: Option<String>,
     #[serde(default = "default_listen_address")]
     pub listen_address: String,
     pub channels: RwLock<HashSet<String>>,
+    #[serde(default)]
+    pub channel_retention_days: HashMap<String, u32>,
     #[serde(rename = "clientID")]
     pub client_id: String,
     pub client_secret: String,
     pub admins: Vec<String>,
     #[serde(default)]
     pub opt_out: DashMap<String, bool>,
+    #[serde(default)]
+    pub channel_opt_out: DashMap<String, HashSet<String>>,
     #[serde(rename = "adminAPIKey")]
     pub admin_api_key: Option<String>,
+    #[serde(default, rename = "adminAPIKeys")]
+    pub admin_api_keys: Vec<AdminApiKey>,
+    #[serde(default)]
+    pub bot_login: Option<String>,
+    #[serde(default)]
+    pub bot_oauth_token: Option<String>,
+    #[serde(default)]
+    pub bot_refresh_token: Option<String>,
+    #[serde(default)]
+    pub bot_token_file: Option<String>,
 }
 
 impl Config {
     pub fn load() -> anyhow::Result<Self> {
         let contents = fs::read_to_string(CONFIG_FILE_NAME)
             .with_context(|| format!("Failed to load config from {CONFIG_FILE_NAME}"))?;
-        serde_json::from_str(&contents).context("Config deserializtion error")
+        let config: Self =
+            serde_json::from_str(&contents).context("Config deserializtion error")?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        if let Some((channel_id, _)) = self
+            .channel_retention_days
+            .iter()
+            .find(|(_, days)| **days == 0)
+        {
+            bail!("The retention of channel {channel_id} must be at least one day");
+        }
+
+        Ok(())
+    }
+
+    /// Name of the admin API key, if the key exists and is allowed to use the given scope
+    pub fn authorize_admin_key(&self, key: &str, scope: AdminScope) -> Option<&str> {
+        if self.admin_api_key.as_deref() == Some(key) {
+            return Some(LEGACY_ADMIN_KEY_NAME);
+        }
+
+        self.admin_api_keys
+            .iter()
+            .find(|admin_key| admin_key.key == key && admin_key.scopes.contains(&scope))
+            .map(|admin_key| admin_key.name.as_str())
+    }
+
+    /// Any admin API key which is allowed to use the given scope
+    pub fn admin_key(&self, scope: AdminScope) -> Option<&str> {
+        self.admin_api_key.as_deref().or_else(|| {
+            self.admin_api_keys
+                .iter()
+                .find(|admin_key| admin_key.scopes.contains(&scope))
+                .map(|admin_key| admin_key.key.as_str())
+        })
+    }
+
+    /// Ids of users whose logs must not be shown in the given channel
+    pub fn opted_out_user_ids(&self, channel_id: &str) -> Vec<String> {
+        let mut user_ids: Vec<String> = self
+            .opt_out
+            .iter()
+            .map(|entry| entry.key().clone())
+            .collect();
+        user_ids.extend(
+            self.channel_opt_out
+                .iter()
+                .filter(|entry| entry.value().contains(channel_id))
+                .map(|entry| entry.key().clone()),
+        );
+        user_ids
+    }
+
+    /// Channels which are only in the other config and channels which are only in this one
+    pub fn channel_changes(&self, other: &Config) -> (Vec<String>, Vec<String>) {
+        let channels = self.channels.read().unwrap();
+        let other_channels = other.channels.read().unwrap();
+
+        let mut joined: Vec<String> = other_channels.difference(&channels).cloned().collect();
+        let mut parted: Vec<String> = channels.difference(&other_channels).cloned().collect();
+        joined.sort_unstable();
+        parted.sort_unstable();
+
+        (joined, parted)
     }
 
     pub fn save(&self) -> anyhow::Result<()> {
@@ -45,6 +132,23 @@ impl Config {
     }
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct AdminApiKey {
+    pub name: String,
+    pub key: String,
+    pub scopes: HashSet<AdminScope>,
+}
+
+#[derive(Serialize, Deserialize, AsRefStr, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[serde(rename_all = "kebab-case")]
+#[strum(serialize_all = "kebab-case")]
+pub enum AdminScope {
+    Channels,
+    Optout,
+    Purge,
+    ReadPrivate,
+}
+
 fn default_listen_address() -> String {
     String::from("0.0.0.0:8025")
 }
@@ -52,3 +156,60 @@ fn default_listen_address() -> String {
 fn clickhouse_flush_interval() -> u64 {
     10
 }
+
+#[cfg(test)]
+mod tests {
+    use super::Config;
+    use pretty_assertions::assert_eq;
+    use serde_json::json;
+
+    fn config_with(values: serde_json::Value) -> Config {
+        let mut config = json!({
+            "clickhouseUrl": "http://localhost:8123",
+            "clickhouseDb": "rustlog",
+            "channels": [],
+            "clientID": "id",
+            "clientSecret": "secret",
+            "admins": []
+        });
+        config
+            .as_object_mut()
+            .unwrap()
+            .extend(values.as_object().unwrap().clone());
+
+        serde_json::from_value(config).unwrap()
+    }
+
+    #[test]
+    fn opted_out_user_ids_of_channel() {
+        let config = config_with(json!({
+            "optOut": { "1": true },
+            "channelOptOut": { "2": ["10"], "3": ["11"] }
+        }));
+
+        let mut user_ids = config.opted_out_user_ids("10");
+        user_ids.sort();
+
+        assert_eq!(user_ids, vec!["1", "2"]);
+    }
+
+    #[test]
+    fn channel_changes_between_configs() {
+        let old_config = config_with(json!({ "channels": ["1", "2", "3"] }));
+        let new_config = config_with(json!({ "channels": ["2", "3", "4", "5"] }));
+
+        let (joined, parted) = old_config.channel_changes(&new_config);
+
+        assert_eq!(joined, vec!["4", "5"]);
+        assert_eq!(parted, vec!["1"]);
+    }
+
+    #[test]
+    fn reject_zero_retention() {
+        let config = config_with(json!({ "channelRetentionDays": { "1": 30 } }));
+        assert!(config.validate().is_ok());
+
+        let config = config_with(json!({ "channelRetentionDays": { "1": 30, "2": 0 } }));
+        assert!(config.validate().is_err());
+    }
+}
@@ -1,7 +1,8 @@
 mod migratable;
 
-use crate::Result;
+use crate::{logs::schema::message::MessageType, Result};
 use clickhouse::Client;
+use strum::IntoEnumIterator;
 use tracing::{debug, info};
 
 use self::migratable::Migratable;
@@ -45,6 +46,554 @@ MATERIALIZE PROJECTION channel_log_dates",
     )
     .await?;
 
+    run_migration(
+        db,
+        "4_add_message_text_column",
+        r"
+ALTER TABLE message
+ADD COLUMN text String
+MATERIALIZED extract(raw, ' (?:PRIVMSG|USERNOTICE) #[^ ]+ :(.*)$')
+CODEC(ZSTD(5))",
+    )
+    .await?;
+
+    run_migration(
+        db,
+        "5_add_message_text_index",
+        "
+ALTER TABLE message
+ADD INDEX text_ngram_idx lowerUTF8(text) TYPE ngrambf_v1(3, 65536, 2, 0) GRANULARITY 1",
+    )
+    .await?;
+
+    run_migration(
+        db,
+        "6_materialize_message_text",
+        "
+ALTER TABLE message
+MATERIALIZE COLUMN text",
+    )
+    .await?;
+
+    run_migration(
+        db,
+        "7_materialize_message_text_index",
+        "
+ALTER TABLE message
+MATERIALIZE INDEX text_ngram_idx",
+    )
+    .await?;
+
+    run_migration(
+        db,
+        "8_add_message_structured_columns",
+        "
+ALTER TABLE message
+ADD COLUMN message_type UInt8 DEFAULT 0,
+ADD COLUMN login String CODEC(ZSTD(5)),
+ADD COLUMN display_name String CODEC(ZSTD(5)),
+ADD COLUMN message_id String CODEC(ZSTD(5))",
+    )
+    .await?;
+
+    run_migration(
+        db,
+        "9_backfill_message_structured_columns",
+        &*backfill_structured_columns_query(),
+    )
+    .await?;
+
+    run_migration(
+        db,
+        "10_create_username_history",
+        "
+CREATE TABLE IF NOT EXISTS username_history
+(
+    user_id String,
+    user_login String,
+    display_name String,
+    first_timestamp SimpleAggregateFunction(min, DateTime64(3)),
+    last_timestamp SimpleAggregateFunction(max, DateTime64(3)),
+    INDEX user_login_idx user_login TYPE bloom_filter GRANULARITY 4
+)
+ENGINE = AggregatingMergeTree
+ORDER BY (user_id, user_login, display_name)",
+    )
+    .await?;
+
+    run_migration(
+        db,
+        "11_create_username_history_mv",
+        &*username_history_view_query(),
+    )
+    .await?;
+
+    run_migration(
+        db,
+        "12_backfill_username_history",
+        &*backfill_username_history_query(),
+    )
+    .await?;
+
+    run_migration(
+        db,
+        "13_create_channel_user_daily_stats",
+        "
+CREATE TABLE IF NOT EXISTS channel_user_daily_stats
+(
+    channel_id LowCardinality(String),
+    date Date,
+    user_id String,
+    messages SimpleAggregateFunction(sum, UInt64)
+)
+ENGINE = AggregatingMergeTree
+PARTITION BY toYYYYMM(date)
+ORDER BY (channel_id, date, user_id)",
+    )
+    .await?;
+
+    run_migration(
+        db,
+        "14_create_channel_user_daily_stats_mv",
+        &*format!(
+            "
+CREATE MATERIALIZED VIEW IF NOT EXISTS channel_user_daily_stats_mv
+TO channel_user_daily_stats
+AS SELECT
+    channel_id,
+    toDate(timestamp) AS date,
+    user_id,
+    count() AS messages
+FROM message
+WHERE message_type = {}
+GROUP BY channel_id, date, user_id",
+            MessageType::PrivMsg as u8
+        ),
+    )
+    .await?;
+
+    run_migration(
+        db,
+        "15_backfill_channel_user_daily_stats",
+        &*format!(
+            "
+INSERT INTO channel_user_daily_stats
+SELECT
+    channel_id,
+    toDate(timestamp) AS date,
+    user_id,
+    count() AS messages
+FROM message
+WHERE {RAW_COMMAND_EXPR} = 'PRIVMSG'
+GROUP BY channel_id, date, user_id"
+        ),
+    )
+    .await?;
+
+    run_migration(
+        db,
+        "16_create_justlog_migration_checkpoint",
+        "
+CREATE TABLE IF NOT EXISTS justlog_migration_checkpoint
+(
+    channel_id String,
+    date Date,
+    lines UInt64,
+    migrated_at DateTime DEFAULT now()
+)
+ENGINE = ReplacingMergeTree(migrated_at)
+ORDER BY (channel_id, date)",
+    )
+    .await?;
+
+    // Sorting keys can only be extended with plain columns, so the table is rebuilt with a
+    // deduplicating engine and swapped in. This copies all logs, which needs enough free disk
+    // space for a second copy of the table and can take a long time on large instances.
+    // Duplicated rows are only merged in the background, so queries still deduplicate with
+    // the key, and the stats count distinct keys instead of rows.
+    run_migration(
+        db,
+        "17_create_message_deduplicated",
+        &*format!(
+            r"
+CREATE TABLE IF NOT EXISTS message_deduplicated
+(
+    channel_id LowCardinality(String),
+    user_id String CODEC(ZSTD(5)),
+    timestamp DateTime64(3) CODEC (DoubleDelta, ZSTD(5)),
+    raw String CODEC(ZSTD(5)),
+    text String MATERIALIZED extract(raw, ' (?:PRIVMSG|USERNOTICE) #[^ ]+ :(.*)$') CODEC(ZSTD(5)),
+    message_type UInt8 DEFAULT 0,
+    login String CODEC(ZSTD(5)),
+    display_name String CODEC(ZSTD(5)),
+    message_id String CODEC(ZSTD(5)),
+    dedup_key UInt64 MATERIALIZED {},
+    INDEX text_ngram_idx lowerUTF8(text) TYPE ngrambf_v1(3, 65536, 2, 0) GRANULARITY 1,
+    PROJECTION channel_log_dates
+    (SELECT channel_id, toDateTime(toStartOfDay(timestamp)) as date GROUP BY channel_id, date)
+)
+ENGINE = ReplacingMergeTree
+PARTITION BY toYYYYMM(timestamp)
+ORDER BY (channel_id, user_id, timestamp, dedup_key)",
+            dedup_key_expr()
+        ),
+    )
+    .await?;
+
+    run_migration(db, "18_copy_message_deduplicated", copy_message_partitions).await?;
+
+    run_migration(
+        db,
+        "19_drop_username_history_mv",
+        "DROP VIEW IF EXISTS username_history_mv",
+    )
+    .await?;
+
+    run_migration(
+        db,
+        "20_drop_channel_user_daily_stats_mv",
+        "DROP VIEW IF EXISTS channel_user_daily_stats_mv",
+    )
+    .await?;
+
+    run_migration(
+        db,
+        "21_swap_message_deduplicated",
+        "RENAME TABLE message TO message_duplicated, message_deduplicated TO message",
+    )
+    .await?;
+
+    run_migration(
+        db,
+        "22_recreate_username_history_mv",
+        &*username_history_view_query(),
+    )
+    .await?;
+
+    run_migration(
+        db,
+        "23_drop_message_duplicated",
+        "DROP TABLE IF EXISTS message_duplicated",
+    )
+    .await?;
+
+    // Replies sent before Twitch introduced threads only reference their direct parent
+    run_migration(
+        db,
+        "24_add_message_reply_thread_id",
+        &*format!(
+            "
+ALTER TABLE message
+ADD COLUMN reply_thread_id String
+MATERIALIZED if({RAW_REPLY_THREAD_PARENT_TAG_EXPR} != '', {RAW_REPLY_THREAD_PARENT_TAG_EXPR}, {RAW_REPLY_PARENT_TAG_EXPR})
+CODEC(ZSTD(5))"
+        ),
+    )
+    .await?;
+
+    run_migration(
+        db,
+        "25_add_message_id_indexes",
+        "
+ALTER TABLE message
+ADD INDEX message_id_idx message_id TYPE bloom_filter GRANULARITY 4,
+ADD INDEX reply_thread_id_idx reply_thread_id TYPE bloom_filter GRANULARITY 4",
+    )
+    .await?;
+
+    run_migration(
+        db,
+        "26_materialize_message_reply_thread_id",
+        "
+ALTER TABLE message
+MATERIALIZE COLUMN reply_thread_id",
+    )
+    .await?;
+
+    run_migration(
+        db,
+        "27_materialize_message_id_indexes",
+        "
+ALTER TABLE message
+MATERIALIZE INDEX message_id_idx,
+MATERIALIZE INDEX reply_thread_id_idx",
+    )
+    .await?;
+
+    // User statistics and exports filter by user id across all channels
+    run_migration(
+        db,
+        "28_add_message_user_id_index",
+        "
+ALTER TABLE message
+ADD INDEX user_id_idx user_id TYPE bloom_filter GRANULARITY 4",
+    )
+    .await?;
+
+    run_migration(
+        db,
+        "29_materialize_message_user_id_index",
+        "
+ALTER TABLE message
+MATERIALIZE INDEX user_id_idx",
+    )
+    .await?;
+
+    // The first version of the stats counted every row, so messages written while the backfill
+    // was running and duplicated messages were counted twice. Stats now keep the set of counted
+    // messages, so rows written both by the view and by the backfill are merged instead.
+    // The old view has already been dropped while replacing the message table.
+    run_migration(
+        db,
+        "30_drop_channel_user_daily_stats",
+        "DROP TABLE IF EXISTS channel_user_daily_stats",
+    )
+    .await?;
+
+    run_migration(
+        db,
+        "31_create_channel_user_daily_stats",
+        "
+CREATE TABLE IF NOT EXISTS channel_user_daily_stats
+(
+    channel_id LowCardinality(String),
+    date Date,
+    user_id String,
+    messages AggregateFunction(uniqExact, UInt64)
+)
+ENGINE = AggregatingMergeTree
+PARTITION BY toYYYYMM(date)
+ORDER BY (channel_id, date, user_id)",
+    )
+    .await?;
+
+    run_migration(
+        db,
+        "32_create_channel_user_daily_stats_mv",
+        &*format!(
+            "
+CREATE MATERIALIZED VIEW IF NOT EXISTS channel_user_daily_stats_mv
+TO channel_user_daily_stats
+AS {}",
+            channel_user_daily_stats_query(&format!(
+                "message_type = {}",
+                MessageType::PrivMsg as u8
+            ))
+        ),
+    )
+    .await?;
+
+    // Reads the command from `raw`, as the structured columns of old rows might not be backfilled
+    run_migration(
+        db,
+        "33_backfill_channel_user_daily_stats",
+        &*format!(
+            "
+INSERT INTO channel_user_daily_stats
+{}",
+            channel_user_daily_stats_query(&format!("{RAW_COMMAND_EXPR} = 'PRIVMSG'"))
+        ),
+    )
+    .await?;
+
+    // Checkpoints are kept per source, so importing logs of another format or folder
+    // does not skip the days which have been imported from a different source
+    run_migration(
+        db,
+        "34_create_migration_checkpoint",
+        "
+CREATE TABLE IF NOT EXISTS migration_checkpoint
+(
+    source_format LowCardinality(String),
+    source_path String,
+    channel_id String,
+    date Date,
+    lines UInt64,
+    migrated_at DateTime DEFAULT now()
+)
+ENGINE = ReplacingMergeTree(migrated_at)
+ORDER BY (source_format, source_path, channel_id, date)",
+    )
+    .await?;
+
+    // The folder of previous justlog migrations is unknown, they are kept with an empty path
+    run_migration(
+        db,
+        "35_copy_justlog_migration_checkpoint",
+        "
+INSERT INTO migration_checkpoint (source_format, source_path, channel_id, date, lines, migrated_at)
+SELECT 'justlog', '', channel_id, date, lines, migrated_at
+FROM justlog_migration_checkpoint",
+    )
+    .await?;
+
+    run_migration(
+        db,
+        "36_drop_justlog_migration_checkpoint",
+        "DROP TABLE IF EXISTS justlog_migration_checkpoint",
+    )
+    .await?;
+
+    Ok(())
+}
+
+// Expressions for extracting message details from the raw IRC message in SQL
+const RAW_COMMAND_EXPR: &str = "extract(raw, '^(?:@[^ ]* )?(?::[^ ]* )?([A-Z]+) ')";
+const RAW_PREFIX_NICK_EXPR: &str = "extract(raw, '^(?:@[^ ]* )?:([^! ]+)!')";
+const RAW_LOGIN_TAG_EXPR: &str = "extract(raw, '^@(?:[^ ]*;)?login=([^; ]*)')";
+const RAW_DISPLAY_NAME_EXPR: &str = "extract(raw, '^@(?:[^ ]*;)?display-name=([^; ]*)')";
+const RAW_ID_TAG_EXPR: &str = "extract(raw, '^@(?:[^ ]*;)?id=([^; ]*)')";
+const RAW_REPLY_PARENT_TAG_EXPR: &str = "extract(raw, '^@(?:[^ ]*;)?reply-parent-msg-id=([^; ]*)')";
+const RAW_REPLY_THREAD_PARENT_TAG_EXPR: &str =
+    "extract(raw, '^@(?:[^ ]*;)?reply-thread-parent-msg-id=([^; ]*)')";
+
+/// Messages with the same key are the same message logged more than once,
+/// messages without an id (such as timeouts) are identified by their whole line
+fn dedup_key_expr() -> String {
+    format!("cityHash64(if({RAW_ID_TAG_EXPR} != '', {RAW_ID_TAG_EXPR}, raw))")
+}
+
+fn username_history_view_query() -> String {
+    format!(
+        "
+CREATE MATERIALIZED VIEW IF NOT EXISTS username_history_mv
+TO username_history
+AS SELECT
+    user_id,
+    login AS user_login,
+    display_name,
+    min(timestamp) AS first_timestamp,
+    max(timestamp) AS last_timestamp
+FROM message
+WHERE message_type IN ({}, {}) AND user_id != '' AND login != ''
+GROUP BY user_id, user_login, display_name",
+        MessageType::PrivMsg as u8,
+        MessageType::UserNotice as u8
+    )
+}
+
+/// Expressions for the structured columns, read from `raw`
+fn structured_column_exprs() -> [(&'static str, String); 4] {
+    let command = RAW_COMMAND_EXPR;
+    let type_names = MessageType::iter()
+        .map(|message_type| format!("'{}'", message_type.as_ref()))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let type_values = MessageType::iter()
+        .map(|message_type| (message_type as u8).to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    [
+        (
+            "message_type",
+            format!("transform({command}, [{type_names}], [{type_values}], 0)"),
+        ),
+        (
+            "login",
+            format!(
+                "multiIf(
+        {command} = 'PRIVMSG', {RAW_PREFIX_NICK_EXPR},
+        {command} = 'CLEARCHAT', extract(raw, ' CLEARCHAT #[^ ]+ :([^ ]+)$'),
+        {RAW_LOGIN_TAG_EXPR}
+    )"
+            ),
+        ),
+        ("display_name", RAW_DISPLAY_NAME_EXPR.to_owned()),
+        ("message_id", RAW_ID_TAG_EXPR.to_owned()),
+    ]
+}
+
+fn backfill_structured_columns_query() -> String {
+    let assignments = structured_column_exprs()
+        .map(|(column, expr)| format!("    {column} = {expr}"))
+        .join(",\n");
+
+    format!(
+        "
+ALTER TABLE message
+UPDATE
+{assignments}
+WHERE message_type = 0"
+    )
+}
+
+/// Counts the chat messages matched by `privmsg_condition`
+fn channel_user_daily_stats_query(privmsg_condition: &str) -> String {
+    format!(
+        "SELECT
+    channel_id,
+    toDate(timestamp) AS date,
+    user_id,
+    uniqExactState({}) AS messages
+FROM message
+WHERE {privmsg_condition}
+GROUP BY channel_id, date, user_id",
+        dedup_key_expr()
+    )
+}
+
+/// Reads logins from `raw`, as the structured columns might still be getting backfilled
+fn backfill_username_history_query() -> String {
+    let command = RAW_COMMAND_EXPR;
+
+    format!(
+        "
+INSERT INTO username_history
+SELECT
+    user_id,
+    if({command} = 'PRIVMSG', {RAW_PREFIX_NICK_EXPR}, {RAW_LOGIN_TAG_EXPR}) AS user_login,
+    {RAW_DISPLAY_NAME_EXPR} AS display_name,
+    min(timestamp) AS first_timestamp,
+    max(timestamp) AS last_timestamp
+FROM message
+WHERE {command} IN ('PRIVMSG', 'USERNOTICE') AND user_id != ''
+GROUP BY user_id, user_login, display_name
+HAVING user_login != ''"
+    )
+}
+
+/// Copies the logs one partition at a time, so a failed copy can be continued
+async fn copy_message_partitions(db: &Client) -> anyhow::Result<()> {
+    let partition_ids: Vec<String> = db
+        .query("SELECT DISTINCT partition_id FROM system.parts WHERE database = currentDatabase() AND table = 'message' AND active ORDER BY partition_id")
+        .fetch_all()
+        .await?;
+    let partition_count = partition_ids.len();
+
+    // The backfill of the structured columns is a mutation which might not have reached every
+    // part yet, and it is dropped along with the old table, so the columns are read from `raw`
+    let columns = structured_column_exprs()
+        .map(|(column, expr)| format!("{expr} AS {column}"))
+        .join(",\n    ");
+    let copy_query = format!(
+        "
+INSERT INTO message_deduplicated (channel_id, user_id, timestamp, raw, message_type, login, display_name, message_id)
+SELECT
+    channel_id,
+    user_id,
+    timestamp,
+    raw,
+    {columns}
+FROM message
+WHERE _partition_id = ?"
+    );
+
+    for (i, partition_id) in partition_ids.into_iter().enumerate() {
+        info!(
+            "Copying partition {partition_id} ({}/{partition_count})",
+            i + 1
+        );
+
+        // Remove rows of a previous attempt which has failed
+        db.query("ALTER TABLE message_deduplicated DROP PARTITION ID ?")
+            .bind(&partition_id)
+            .execute()
+            .await?;
+
+        db.query(&copy_query).bind(&partition_id).execute().await?;
+    }
+
     Ok(())
 }
 
@@ -89,3 +638,84 @@ ORDER BY name",
     .await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        dedup_key_expr, RAW_ID_TAG_EXPR, RAW_REPLY_PARENT_TAG_EXPR,
+        RAW_REPLY_THREAD_PARENT_TAG_EXPR,
+    };
+    use pretty_assertions::assert_eq;
+    use regex::Regex;
+
+    const PRIVMSG_LINE: &str = "@badge-info=;badges=;color=#FF0000;display-name=Test;emotes=;first-msg=0;flags=;id=abc-123;mod=0;reply-parent-display-name=Other;reply-parent-msg-body=hi;reply-parent-msg-id=parent-1;reply-parent-user-id=3;reply-parent-user-login=other;reply-thread-parent-msg-id=root-1;reply-thread-parent-user-login=root;returning-chatter=0;room-id=1;subscriber=0;tmi-sent-ts=1686000000000;turbo=0;user-id=2;user-type= :test!test@test.tmi.twitch.tv PRIVMSG #channel :@other hello";
+    const CLEARMSG_LINE: &str = "@login=test;room-id=;target-msg-id=abc-123;tmi-sent-ts=1686000000000 :tmi.twitch.tv CLEARMSG #channel :hello";
+
+    /// Evaluates an `extract(raw, '...')` expression the way ClickHouse does
+    fn extract<'a>(expr: &str, raw: &'a str) -> &'a str {
+        let pattern = expr
+            .strip_prefix("extract(raw, '")
+            .and_then(|expr| expr.strip_suffix("')"))
+            .expect("Not an extract expression");
+
+        Regex::new(pattern)
+            .unwrap()
+            .captures(raw)
+            .and_then(|captures| captures.get(1))
+            .map_or("", |capture| capture.as_str())
+    }
+
+    #[test]
+    fn dedup_key_uses_message_id() {
+        assert_eq!(extract(RAW_ID_TAG_EXPR, PRIVMSG_LINE), "abc-123");
+        assert_eq!(
+            extract(
+                RAW_ID_TAG_EXPR,
+                "@id=abc-123;room-id=1 :tmi.twitch.tv USERNOTICE #channel"
+            ),
+            "abc-123"
+        );
+    }
+
+    #[test]
+    fn dedup_key_falls_back_to_raw_line() {
+        // The id of the deleted message must not be mistaken for the id of the line
+        assert_eq!(extract(RAW_ID_TAG_EXPR, CLEARMSG_LINE), "");
+        assert_eq!(
+            dedup_key_expr(),
+            format!("cityHash64(if({RAW_ID_TAG_EXPR} != '', {RAW_ID_TAG_EXPR}, raw))")
+        );
+    }
+
+    /// Mirrors the `reply_thread_id` column
+    fn reply_thread_id(raw: &str) -> &str {
+        match extract(RAW_REPLY_THREAD_PARENT_TAG_EXPR, raw) {
+            "" => extract(RAW_REPLY_PARENT_TAG_EXPR, raw),
+            thread_parent_id => thread_parent_id,
+        }
+    }
+
+    #[test]
+    fn reply_thread_id_of_nested_reply() {
+        assert_eq!(extract(RAW_REPLY_PARENT_TAG_EXPR, PRIVMSG_LINE), "parent-1");
+        assert_eq!(reply_thread_id(PRIVMSG_LINE), "root-1");
+    }
+
+    #[test]
+    fn reply_thread_id_of_reply_to_root() {
+        let raw = "@display-name=Test;id=abc-123;reply-parent-msg-id=root-1;reply-parent-user-login=root;reply-thread-parent-msg-id=root-1;reply-thread-parent-user-login=root;room-id=1;tmi-sent-ts=1686000000000;user-id=2 :test!test@test.tmi.twitch.tv PRIVMSG #channel :@root hi";
+        assert_eq!(reply_thread_id(raw), "root-1");
+    }
+
+    #[test]
+    fn reply_thread_id_without_thread_tag() {
+        // Replies sent before threads existed only reference their direct parent
+        let raw = "@display-name=Test;id=abc-123;reply-parent-msg-id=parent-1;reply-parent-user-login=other;room-id=1;tmi-sent-ts=1686000000000;user-id=2 :test!test@test.tmi.twitch.tv PRIVMSG #channel :@other hi";
+        assert_eq!(reply_thread_id(raw), "parent-1");
+    }
+
+    #[test]
+    fn no_reply_thread_id() {
+        assert_eq!(reply_thread_id(CLEARMSG_LINE), "");
+    }
+}
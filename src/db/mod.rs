@@ -1,22 +1,41 @@
 mod migrations;
+pub mod retention;
 pub mod schema;
+mod spool;
 pub mod writer;
 
 pub use migrations::run as setup_db;
 
+use self::schema::MESSAGES_TABLE;
+
 use crate::{
     error::Error,
     logs::{
-        schema::{ChannelLogDate, UserLogDate},
+        schema::{message::MessageType, ChannelLogDate, UserLogDate},
         stream::LogsStream,
     },
-    web::schema::AvailableLogDate,
+    web::schema::{
+        AvailableLogDate, ChannelDayStats, MonthlyMessageCount, MutationProgress, PreviousName,
+        UserChannelStats,
+    },
     Result,
 };
-use chrono::{Datelike, NaiveDateTime};
-use clickhouse::Client;
+use chrono::{DateTime, Datelike, Duration, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use clickhouse::{Client, Row};
 use rand::{seq::IteratorRandom, thread_rng};
-use tracing::info;
+use serde::Deserialize;
+use std::time::Duration as StdDuration;
+use tokio::time::sleep;
+use tracing::{info, warn};
+
+/// Rows with the same message id, or the same raw message if it has no id, are duplicates.
+/// They are stored when the same logs are imported more than once or by multiple sources.
+const DEDUPLICATE: &str = "LIMIT 1 BY dedup_key";
+/// Tables which contain channel logs
+const LOG_TABLES: [&str; 2] = [MESSAGES_TABLE, "channel_user_daily_stats"];
+/// Mutation ids are only unique per table, so they are identified together with their table
+const MUTATION_KEY_EXPR: &str = "concat(table, '/', mutation_id)";
+const MUTATION_PROGRESS_INTERVAL_SECONDS: u64 = 5;
 
 pub async fn read_channel(
     db: &Client,
@@ -27,7 +46,7 @@ pub async fn read_channel(
     offset: Option<u64>,
 ) -> Result<LogsStream> {
     let suffix = if reverse { "DESC" } else { "ASC" };
-    let mut query = format!("SELECT raw FROM message WHERE channel_id = ? AND toStartOfDay(timestamp) = ? ORDER BY timestamp {suffix}");
+    let mut query = format!("SELECT raw FROM message WHERE channel_id = ? AND toStartOfDay(timestamp) = ? ORDER BY timestamp {suffix} {DEDUPLICATE}");
     apply_limit_offset(&mut query, limit, offset);
 
     let cursor = db
@@ -48,7 +67,7 @@ pub async fn read_user(
     offset: Option<u64>,
 ) -> Result<LogsStream> {
     let suffix = if reverse { "DESC" } else { "ASC" };
-    let mut query = format!("SELECT raw FROM message WHERE channel_id = ? AND user_id = ? AND toStartOfMonth(timestamp) = ? ORDER BY timestamp {suffix}");
+    let mut query = format!("SELECT raw FROM message WHERE channel_id = ? AND user_id = ? AND toStartOfMonth(timestamp) = ? ORDER BY timestamp {suffix} {DEDUPLICATE}");
     apply_limit_offset(&mut query, limit, offset);
 
     let cursor = db
@@ -61,6 +80,157 @@ pub async fn read_user(
     LogsStream::new_cursor(cursor).await
 }
 
+pub async fn read_channel_range(
+    db: &Client,
+    channel_id: &str,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    reverse: bool,
+    limit: Option<u64>,
+    offset: Option<u64>,
+) -> Result<LogsStream> {
+    let suffix = if reverse { "DESC" } else { "ASC" };
+    let mut query = format!("SELECT raw FROM message WHERE channel_id = ? AND timestamp >= fromUnixTimestamp64Milli(toInt64(?)) AND timestamp < fromUnixTimestamp64Milli(toInt64(?)) ORDER BY timestamp {suffix} {DEDUPLICATE}");
+    apply_limit_offset(&mut query, limit, offset);
+
+    let cursor = db
+        .query(&query)
+        .bind(channel_id)
+        .bind(from.timestamp_millis())
+        .bind(to.timestamp_millis())
+        .fetch()?;
+    LogsStream::new_cursor(cursor).await
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn read_user_range(
+    db: &Client,
+    channel_id: &str,
+    user_id: &str,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    reverse: bool,
+    limit: Option<u64>,
+    offset: Option<u64>,
+) -> Result<LogsStream> {
+    let suffix = if reverse { "DESC" } else { "ASC" };
+    let mut query = format!("SELECT raw FROM message WHERE channel_id = ? AND user_id = ? AND timestamp >= fromUnixTimestamp64Milli(toInt64(?)) AND timestamp < fromUnixTimestamp64Milli(toInt64(?)) ORDER BY timestamp {suffix} {DEDUPLICATE}");
+    apply_limit_offset(&mut query, limit, offset);
+
+    let cursor = db
+        .query(&query)
+        .bind(channel_id)
+        .bind(user_id)
+        .bind(from.timestamp_millis())
+        .bind(to.timestamp_millis())
+        .fetch()?;
+    LogsStream::new_cursor(cursor).await
+}
+
+/// Logs of the user in all channels, ordered by channel. Uses the user id skip index, as rows are sorted by channel first
+pub async fn read_all_user_logs(db: &Client, user_id: &str) -> Result<LogsStream> {
+    let cursor = db
+        .query(&format!(
+            "SELECT raw FROM message WHERE user_id = ? ORDER BY channel_id, timestamp ASC {DEDUPLICATE}"
+        ))
+        .bind(user_id)
+        .fetch()?;
+    LogsStream::new_cursor(cursor).await
+}
+
+/// Reads the reply thread the message belongs to, which consists of the root message and all replies to it
+pub async fn read_reply_thread(
+    db: &Client,
+    channel_id: &str,
+    message_id: &str,
+    reverse: bool,
+    limit: Option<u64>,
+    offset: Option<u64>,
+) -> Result<LogsStream> {
+    let thread_id = db
+        .query(
+            "SELECT reply_thread_id FROM message WHERE channel_id = ? AND message_id = ? LIMIT 1",
+        )
+        .bind(channel_id)
+        .bind(message_id)
+        .fetch_optional::<String>()
+        .await?
+        .ok_or(Error::NotFound)?;
+    // The message is the root of its thread if it is not a reply
+    let root_id = if thread_id.is_empty() {
+        message_id
+    } else {
+        thread_id.as_str()
+    };
+
+    let suffix = if reverse { "DESC" } else { "ASC" };
+    let mut query = format!("SELECT raw FROM message WHERE channel_id = ? AND (message_id = ? OR reply_thread_id = ?) ORDER BY timestamp {suffix} {DEDUPLICATE}");
+    apply_limit_offset(&mut query, limit, offset);
+
+    let cursor = db
+        .query(&query)
+        .bind(channel_id)
+        .bind(root_id)
+        .bind(root_id)
+        .fetch()?;
+    LogsStream::new_cursor(cursor).await
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn search_channel(
+    db: &Client,
+    channel_id: &str,
+    text: &str,
+    user_id: Option<&str>,
+    from: Option<DateTime<Utc>>,
+    to: Option<DateTime<Utc>>,
+    reverse: bool,
+    limit: Option<u64>,
+    offset: Option<u64>,
+) -> Result<LogsStream> {
+    let mut conditions = String::from("channel_id = ? AND lowerUTF8(text) LIKE ?");
+    if user_id.is_some() {
+        conditions.push_str(" AND user_id = ?");
+    }
+    if from.is_some() {
+        conditions.push_str(" AND timestamp >= fromUnixTimestamp64Milli(toInt64(?))");
+    }
+    if to.is_some() {
+        conditions.push_str(" AND timestamp < fromUnixTimestamp64Milli(toInt64(?))");
+    }
+
+    let suffix = if reverse { "DESC" } else { "ASC" };
+    let mut query = format!(
+        "SELECT raw FROM message WHERE {conditions} ORDER BY timestamp {suffix} {DEDUPLICATE}"
+    );
+    apply_limit_offset(&mut query, limit, offset);
+
+    let pattern = format!("%{}%", escape_like_pattern(&text.to_lowercase()));
+
+    let mut query = db.query(&query).bind(channel_id).bind(pattern);
+    if let Some(user_id) = user_id {
+        query = query.bind(user_id);
+    }
+    if let Some(from) = from {
+        query = query.bind(from.timestamp_millis());
+    }
+    if let Some(to) = to {
+        query = query.bind(to.timestamp_millis());
+    }
+
+    let cursor = query.fetch()?;
+    LogsStream::new_cursor(cursor).await
+}
+
+pub async fn read_stored_channel_ids(db: &Client) -> Result<Vec<String>> {
+    let channel_ids = db
+        .query("SELECT DISTINCT channel_id FROM message ORDER BY channel_id")
+        .fetch_all()
+        .await?;
+
+    Ok(channel_ids)
+}
+
 pub async fn read_available_channel_logs(
     db: &Client,
     channel_id: &str,
@@ -139,7 +309,7 @@ pub async fn read_random_user_line(db: &Client, channel_id: &str, user_id: &str)
             "WITH
             (SELECT timestamp FROM message WHERE channel_id = ? AND user_id = ? LIMIT 1 OFFSET ?)
             AS random_timestamp
-            SELECT raw FROM message WHERE channel_id = ? AND user_id = ? AND timestamp = random_timestamp",
+            SELECT raw FROM message WHERE channel_id = ? AND user_id = ? AND timestamp = random_timestamp LIMIT 1",
         )
         .bind(channel_id)
         .bind(user_id)
@@ -174,7 +344,7 @@ pub async fn read_random_channel_line(db: &Client, channel_id: &str) -> Result<S
             "WITH
             (SELECT timestamp FROM message WHERE channel_id = ? LIMIT 1 OFFSET ?)
             AS random_timestamp
-            SELECT raw FROM message WHERE channel_id = ? AND timestamp = random_timestamp",
+            SELECT raw FROM message WHERE channel_id = ? AND timestamp = random_timestamp LIMIT 1",
         )
         .bind(channel_id)
         .bind(offset)
@@ -186,15 +356,392 @@ pub async fn read_random_channel_line(db: &Client, channel_id: &str) -> Result<S
     Ok(text)
 }
 
+pub async fn read_name_history(db: &Client, user_id: &str) -> Result<Vec<PreviousName>> {
+    #[derive(Row, Deserialize)]
+    struct NameHistoryRow {
+        user_login: String,
+        display_name: String,
+        first_timestamp: i64,
+        last_timestamp: i64,
+    }
+
+    let rows = db
+        .query(
+            "SELECT ?fields FROM (
+                SELECT
+                    user_login,
+                    display_name,
+                    toUnixTimestamp64Milli(min(first_timestamp)) AS first_timestamp,
+                    toUnixTimestamp64Milli(max(last_timestamp)) AS last_timestamp
+                FROM username_history
+                WHERE user_id = ?
+                GROUP BY user_login, display_name
+            )
+            ORDER BY last_timestamp DESC",
+        )
+        .bind(user_id)
+        .fetch_all::<NameHistoryRow>()
+        .await?;
+
+    let names = rows
+        .into_iter()
+        .map(|row| PreviousName {
+            user_login: row.user_login,
+            display_name: row.display_name,
+            first_timestamp: Utc
+                .timestamp_millis_opt(row.first_timestamp)
+                .single()
+                .expect("Invalid DateTime"),
+            last_timestamp: Utc
+                .timestamp_millis_opt(row.last_timestamp)
+                .single()
+                .expect("Invalid DateTime"),
+        })
+        .collect();
+
+    Ok(names)
+}
+
+pub async fn read_user_channel_stats(
+    db: &Client,
+    user_id: &str,
+    excluded_channel_ids: &[&str],
+) -> Result<Vec<UserChannelStats>> {
+    #[derive(Row, Deserialize)]
+    struct ChannelStatsRow {
+        channel_id: String,
+        messages: u64,
+        timeouts: u64,
+        bans: u64,
+        first_seen: Option<i64>,
+        last_seen: Option<i64>,
+    }
+
+    let query = format!(
+        "SELECT
+            channel_id,
+            uniqExactIf(dedup_key, message_type = {privmsg}) AS messages,
+            uniqExactIf(dedup_key, message_type = {clearchat} AND position(raw, 'ban-duration=') > 0) AS timeouts,
+            uniqExactIf(dedup_key, message_type = {clearchat} AND position(raw, 'ban-duration=') = 0) AS bans,
+            toUnixTimestamp64Milli(minOrNullIf(timestamp, message_type IN ({privmsg}, {usernotice}))) AS first_seen,
+            toUnixTimestamp64Milli(maxOrNullIf(timestamp, message_type IN ({privmsg}, {usernotice}))) AS last_seen
+        FROM message
+        WHERE user_id = ? AND NOT has(?, channel_id)
+        GROUP BY channel_id
+        ORDER BY messages DESC",
+        privmsg = MessageType::PrivMsg as u8,
+        usernotice = MessageType::UserNotice as u8,
+        clearchat = MessageType::ClearChat as u8,
+    );
+
+    let rows = db
+        .query(&query)
+        .bind(user_id)
+        .bind(excluded_channel_ids)
+        .fetch_all::<ChannelStatsRow>()
+        .await?;
+
+    let stats = rows
+        .into_iter()
+        .map(|row| UserChannelStats {
+            channel_id: row.channel_id,
+            messages: row.messages,
+            timeouts: row.timeouts,
+            bans: row.bans,
+            first_seen: row.first_seen.map(|timestamp| {
+                Utc.timestamp_millis_opt(timestamp)
+                    .single()
+                    .expect("Invalid DateTime")
+            }),
+            last_seen: row.last_seen.map(|timestamp| {
+                Utc.timestamp_millis_opt(timestamp)
+                    .single()
+                    .expect("Invalid DateTime")
+            }),
+        })
+        .collect();
+
+    Ok(stats)
+}
+
+pub async fn read_user_monthly_stats(
+    db: &Client,
+    user_id: &str,
+    excluded_channel_ids: &[&str],
+) -> Result<Vec<MonthlyMessageCount>> {
+    let query = format!(
+        "SELECT toYear(timestamp) AS year, toMonth(timestamp) AS month, uniqExact(dedup_key) AS messages
+        FROM message
+        WHERE user_id = ? AND message_type = {} AND NOT has(?, channel_id)
+        GROUP BY year, month
+        ORDER BY year, month",
+        MessageType::PrivMsg as u8
+    );
+
+    let months = db
+        .query(&query)
+        .bind(user_id)
+        .bind(excluded_channel_ids)
+        .fetch_all::<MonthlyMessageCount>()
+        .await?;
+
+    Ok(months)
+}
+
+pub async fn read_channel_daily_stats(
+    db: &Client,
+    channel_id: &str,
+    from: NaiveDate,
+    to: NaiveDate,
+) -> Result<Vec<ChannelDayStats>> {
+    let days = db
+        .query(
+            "SELECT toString(date) AS day, sum(messages) AS messages, count() AS chatters
+            FROM (
+                SELECT date, user_id, uniqExactMerge(messages) AS messages
+                FROM channel_user_daily_stats
+                WHERE channel_id = ? AND date >= toDate(?) AND date <= toDate(?)
+                GROUP BY date, user_id
+            )
+            GROUP BY date
+            ORDER BY date",
+        )
+        .bind(channel_id)
+        .bind(from.to_string())
+        .bind(to.to_string())
+        .fetch_all::<ChannelDayStats>()
+        .await?;
+
+    Ok(days)
+}
+
+/// Returns user ids and message counts of the most active chatters, skipping the excluded users
+pub async fn read_channel_top_chatters(
+    db: &Client,
+    channel_id: &str,
+    from: NaiveDate,
+    to: NaiveDate,
+    excluded_user_ids: &[&str],
+    limit: u64,
+) -> Result<Vec<(String, u64)>> {
+    #[derive(Row, Deserialize)]
+    struct TopChatterRow {
+        user_id: String,
+        messages: u64,
+    }
+
+    let rows = db
+        .query(
+            "SELECT user_id, uniqExactMerge(messages) AS messages
+            FROM channel_user_daily_stats
+            WHERE channel_id = ? AND date >= toDate(?) AND date <= toDate(?) AND user_id != '' AND NOT has(?, user_id)
+            GROUP BY user_id
+            ORDER BY messages DESC
+            LIMIT ?",
+        )
+        .bind(channel_id)
+        .bind(from.to_string())
+        .bind(to.to_string())
+        .bind(excluded_user_ids)
+        .bind(limit)
+        .fetch_all::<TopChatterRow>()
+        .await?;
+
+    Ok(rows
+        .into_iter()
+        .map(|row| (row.user_id, row.messages))
+        .collect())
+}
+
+/// Finds the user who most recently used the given login
+pub async fn read_user_id_by_previous_login(db: &Client, login: &str) -> Result<Option<String>> {
+    let user_id = db
+        .query(
+            "SELECT user_id FROM username_history WHERE user_login = ? GROUP BY user_id ORDER BY max(last_timestamp) DESC LIMIT 1",
+        )
+        .bind(login)
+        .fetch_optional::<String>()
+        .await?;
+
+    Ok(user_id)
+}
+
 pub async fn delete_user_logs(db: &Client, user_id: &str) -> Result<()> {
     info!("Deleting all logs for user {user_id}");
     db.query("ALTER TABLE message DELETE WHERE user_id = ?")
         .bind(user_id)
         .execute()
         .await?;
+    db.query("ALTER TABLE username_history DELETE WHERE user_id = ?")
+        .bind(user_id)
+        .execute()
+        .await?;
+    db.query("ALTER TABLE channel_user_daily_stats DELETE WHERE user_id = ?")
+        .bind(user_id)
+        .execute()
+        .await?;
+    Ok(())
+}
+
+pub async fn delete_user_channel_logs(db: &Client, channel_id: &str, user_id: &str) -> Result<()> {
+    info!("Deleting logs for user {user_id} in channel {channel_id}");
+    db.query("ALTER TABLE message DELETE WHERE channel_id = ? AND user_id = ?")
+        .bind(channel_id)
+        .bind(user_id)
+        .execute()
+        .await?;
+    db.query("ALTER TABLE channel_user_daily_stats DELETE WHERE channel_id = ? AND user_id = ?")
+        .bind(channel_id)
+        .bind(user_id)
+        .execute()
+        .await?;
     Ok(())
 }
 
+/// Deletes logs of the channel between the given days (inclusive), or all of them if no days are given.
+/// Returns the keys of the started mutations.
+pub async fn delete_channel_logs(
+    db: &Client,
+    channel_id: &str,
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+) -> Result<Vec<String>> {
+    info!("Deleting logs in channel {channel_id} (from {from:?} to {to:?})");
+
+    let mut message_conditions = String::from("channel_id = ?");
+    let mut stats_conditions = String::from("channel_id = ?");
+    if from.is_some() {
+        message_conditions.push_str(" AND timestamp >= fromUnixTimestamp64Milli(toInt64(?))");
+        stats_conditions.push_str(" AND date >= toDate(?)");
+    }
+    if to.is_some() {
+        message_conditions.push_str(" AND timestamp < fromUnixTimestamp64Milli(toInt64(?))");
+        stats_conditions.push_str(" AND date <= toDate(?)");
+    }
+
+    let mut message_query = db
+        .query(&format!(
+            "ALTER TABLE message DELETE WHERE {message_conditions}"
+        ))
+        .bind(channel_id);
+    let mut stats_query = db
+        .query(&format!(
+            "ALTER TABLE channel_user_daily_stats DELETE WHERE {stats_conditions}"
+        ))
+        .bind(channel_id);
+    if let Some(from) = from {
+        message_query = message_query.bind(start_of_day_millis(from));
+        stats_query = stats_query.bind(from.to_string());
+    }
+    if let Some(to) = to {
+        message_query = message_query.bind(start_of_day_millis(to + Duration::days(1)));
+        stats_query = stats_query.bind(to.to_string());
+    }
+
+    // ClickHouse does not return the id of a new mutation, so they are found by their
+    // command, which contains the channel condition with the channel id inlined
+    let started_at = db
+        .query("SELECT toUnixTimestamp(now())")
+        .fetch_one::<u32>()
+        .await?;
+    message_query.execute().await?;
+    stats_query.execute().await?;
+
+    let channel_condition = format!(
+        "channel_id = '{}'",
+        channel_id.replace('\\', "\\\\").replace('\'', "\\'")
+    );
+    let started_keys = db
+        .query(&format!(
+            "SELECT {MUTATION_KEY_EXPR} FROM system.mutations
+            WHERE database = currentDatabase() AND has(?, table) AND create_time >= toDateTime(?)
+            AND startsWith(command, 'DELETE') AND position(command, ?) > 0"
+        ))
+        .bind(&LOG_TABLES[..])
+        .bind(started_at)
+        .bind(channel_condition)
+        .fetch_all::<String>()
+        .await?;
+
+    Ok(started_keys)
+}
+
+/// Deletes the logs of the channel again once the given mutations have finished and the writer
+/// has flushed, as messages received before leaving the channel can be written after the first deletion
+pub async fn delete_late_channel_logs(
+    db: &Client,
+    channel_id: &str,
+    mutation_keys: &[String],
+    flush_interval: StdDuration,
+) -> Result<Vec<String>> {
+    let (_, mutations_result) =
+        tokio::join!(sleep(flush_interval), wait_for_mutations(db, mutation_keys));
+    mutations_result?;
+
+    delete_channel_logs(db, channel_id, None, None).await
+}
+
+/// The given mutations which have not finished yet
+pub async fn read_pending_mutations(
+    db: &Client,
+    mutation_keys: &[String],
+) -> Result<Vec<MutationProgress>> {
+    let mutations = db
+        .query(&format!(
+            "SELECT table, mutation_id, command, parts_to_do, latest_fail_reason
+            FROM system.mutations
+            WHERE database = currentDatabase() AND NOT is_done AND has(?, {MUTATION_KEY_EXPR})
+            ORDER BY create_time"
+        ))
+        .bind(mutation_keys)
+        .fetch_all()
+        .await?;
+
+    Ok(mutations)
+}
+
+/// Waits until the given mutations have finished, logging their progress
+pub async fn wait_for_mutations(db: &Client, mutation_keys: &[String]) -> Result<()> {
+    loop {
+        let mutations = read_pending_mutations(db, mutation_keys).await?;
+        if mutations.is_empty() {
+            return Ok(());
+        }
+
+        for mutation in &mutations {
+            if !mutation.latest_fail_reason.is_empty() {
+                warn!(
+                    "Mutation {} on {} is failing: {}",
+                    mutation.mutation_id, mutation.table, mutation.latest_fail_reason
+                );
+            }
+        }
+
+        let parts_to_do: i64 = mutations.iter().map(|mutation| mutation.parts_to_do).sum();
+        info!(
+            "Waiting for {} mutations, {parts_to_do} parts left",
+            mutations.len()
+        );
+        sleep(StdDuration::from_secs(MUTATION_PROGRESS_INTERVAL_SECONDS)).await;
+    }
+}
+
+fn start_of_day_millis(date: NaiveDate) -> i64 {
+    Utc.from_utc_datetime(&date.and_hms_opt(0, 0, 0).unwrap())
+        .timestamp_millis()
+}
+
+fn escape_like_pattern(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, '%' | '_' | '\\') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
 fn apply_limit_offset(query: &mut String, limit: Option<u64>, offset: Option<u64>) {
     if let Some(limit) = limit {
         *query = format!("{query} LIMIT {limit}");
@@ -203,3 +750,18 @@ fn apply_limit_offset(query: &mut String, limit: Option<u64>, offset: Option<u64
         *query = format!("{query} OFFSET {offset}");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::escape_like_pattern;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn escape_like_special_characters() {
+        assert_eq!(escape_like_pattern("hello world"), "hello world");
+        assert_eq!(escape_like_pattern("100%"), r"100\%");
+        assert_eq!(escape_like_pattern("snake_case"), r"snake\_case");
+        assert_eq!(escape_like_pattern(r"C:\logs"), r"C:\\logs");
+        assert_eq!(escape_like_pattern(r"%_\"), r"\%\_\\");
+    }
+}
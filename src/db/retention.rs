@@ -0,0 +1,177 @@
+use super::schema::MESSAGES_TABLE;
+use crate::{config::Config, Result, ShutdownRx};
+use arc_swap::ArcSwap;
+use clickhouse::Client;
+use std::{collections::BTreeMap, sync::Arc, time::Duration};
+use tokio::{task::JoinHandle, time::interval};
+use tracing::{debug, error, info};
+
+const RETENTION_CHECK_INTERVAL_SECONDS: u64 = 600;
+
+/// Tables with channel logs and the time expression their retention is based on
+const RETENTION_TABLES: [(&str, &str); 2] = [
+    (MESSAGES_TABLE, "toDateTime(timestamp)"),
+    ("channel_user_daily_stats", "date"),
+];
+
+/// Keeps the TTL rules of the log tables in sync with the retention configured for each channel.
+/// Expired logs are then deleted by ClickHouse when merging, without running any mutations.
+pub fn create_retention_task(
+    db: Client,
+    config: Arc<ArcSwap<Config>>,
+    mut shutdown_rx: ShutdownRx,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut retention_interval =
+            interval(Duration::from_secs(RETENTION_CHECK_INTERVAL_SECONDS));
+        // Retention which has been applied by this process, so unchanged rules are not applied again
+        let mut applied_retention = None;
+
+        loop {
+            tokio::select! {
+                _ = retention_interval.tick() => {
+                    let retention: BTreeMap<String, u32> = config
+                        .load()
+                        .channel_retention_days
+                        .iter()
+                        .map(|(channel_id, days)| (channel_id.clone(), *days))
+                        .collect();
+
+                    if applied_retention.as_ref() == Some(&retention) {
+                        continue;
+                    }
+
+                    match apply_retention(&db, &retention).await {
+                        Ok(true) => {
+                            debug!("Retention of {} channels is applied", retention.len());
+                            applied_retention = Some(retention);
+                        }
+                        Ok(false) => debug!("Waiting for pending mutations before applying retention"),
+                        Err(err) => error!("Could not apply retention: {err}"),
+                    }
+                }
+                Ok(()) = shutdown_rx.changed() => {
+                    debug!("Shutting down retention task");
+                    break;
+                }
+            }
+        }
+    })
+}
+
+/// Returns `false` if the rules could not be changed yet because of pending mutations
+async fn apply_retention(db: &Client, retention: &BTreeMap<String, u32>) -> Result<bool> {
+    // Changing the rules rewrites parts, so it should not compete with a running purge or opt out
+    let pending_mutations = db
+        .query("SELECT count() FROM system.mutations WHERE database = currentDatabase() AND has(?, table) AND NOT is_done")
+        .bind(&RETENTION_TABLES.map(|(table, _)| table)[..])
+        .fetch_one::<u64>()
+        .await?;
+    if pending_mutations > 0 {
+        return Ok(false);
+    }
+
+    for (table, time_expr) in RETENTION_TABLES {
+        // Modifying the rules rewrites every part of the table, so it is skipped if they are unchanged
+        let current_ttl = read_table_ttl(db, table).await?;
+
+        if retention.is_empty() {
+            if current_ttl.is_some() {
+                info!("Removing TTL rules of {table}");
+                db.query(&format!("ALTER TABLE {table} REMOVE TTL"))
+                    .execute()
+                    .await?;
+            }
+        } else if current_ttl.as_deref() != Some(&*formatted_ttl_rules(retention, time_expr)) {
+            info!("Updating TTL rules of {table}");
+            let mut query = db.query(&format!(
+                "ALTER TABLE {table} MODIFY TTL {}",
+                ttl_rules(retention, time_expr)
+            ));
+            for channel_id in retention.keys() {
+                query = query.bind(channel_id);
+            }
+            query.execute().await?;
+        }
+    }
+
+    Ok(true)
+}
+
+async fn read_table_ttl(db: &Client, table: &str) -> Result<Option<String>> {
+    let create_query = db
+        .query("SELECT create_table_query FROM system.tables WHERE database = currentDatabase() AND name = ?")
+        .bind(table)
+        .fetch_one::<String>()
+        .await?;
+
+    // Some versions keep the default action in the stored definition
+    Ok(table_ttl(&create_query).map(|ttl| ttl.replace(" DELETE WHERE ", " WHERE ")))
+}
+
+/// The TTL clause of a `CREATE TABLE` query as it is stored by ClickHouse
+fn table_ttl(create_query: &str) -> Option<&str> {
+    let (_, ttl) = create_query.split_once(" TTL ")?;
+    let ttl = ttl.split_once(" SETTINGS ").map_or(ttl, |(ttl, _)| ttl);
+
+    Some(ttl.trim())
+}
+
+/// One rule per channel, with a placeholder for the channel id
+fn ttl_rules(retention: &BTreeMap<String, u32>, time_expr: &str) -> String {
+    retention
+        .values()
+        .map(|days| format!("{time_expr} + toIntervalDay({days}) DELETE WHERE channel_id = ?"))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// The rules as ClickHouse formats them in the table definition,
+/// which leaves out the default `DELETE` action and inlines the channel ids
+fn formatted_ttl_rules(retention: &BTreeMap<String, u32>, time_expr: &str) -> String {
+    retention
+        .iter()
+        .map(|(channel_id, days)| {
+            format!(
+                "{time_expr} + toIntervalDay({days}) WHERE channel_id = '{}'",
+                channel_id.replace('\\', "\\\\").replace('\'', "\\'")
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{formatted_ttl_rules, table_ttl, ttl_rules};
+    use pretty_assertions::assert_eq;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn ttl_rule_per_channel() {
+        let retention = BTreeMap::from([("2".to_owned(), 7), ("1".to_owned(), 30)]);
+
+        assert_eq!(
+            ttl_rules(&retention, "date"),
+            "date + toIntervalDay(30) DELETE WHERE channel_id = ?, date + toIntervalDay(7) DELETE WHERE channel_id = ?"
+        );
+    }
+
+    #[test]
+    fn unchanged_ttl_rules() {
+        let retention = BTreeMap::from([("1".to_owned(), 30), ("2".to_owned(), 7)]);
+        let create_query = "CREATE TABLE rustlog.channel_user_daily_stats (`channel_id` LowCardinality(String), `date` Date, `user_id` String, `messages` AggregateFunction(uniqExact, UInt64)) ENGINE = AggregatingMergeTree PARTITION BY toYYYYMM(date) ORDER BY (channel_id, date, user_id) TTL date + toIntervalDay(30) WHERE channel_id = '1', date + toIntervalDay(7) WHERE channel_id = '2' SETTINGS index_granularity = 8192";
+
+        assert_eq!(
+            table_ttl(create_query),
+            Some(&*formatted_ttl_rules(&retention, "date"))
+        );
+    }
+
+    #[test]
+    fn table_without_ttl() {
+        let create_query = "CREATE TABLE rustlog.channel_user_daily_stats (`channel_id` LowCardinality(String), `date` Date) ENGINE = AggregatingMergeTree ORDER BY (channel_id, date) SETTINGS index_granularity = 8192";
+
+        assert_eq!(table_ttl(create_query), None);
+    }
+}
@@ -4,10 +4,19 @@ use std::borrow::Cow;
 
 pub const MESSAGES_TABLE: &str = "message";
 
-#[derive(Row, Serialize, Deserialize, Debug)]
+#[derive(Row, Serialize, Deserialize, Debug, Clone)]
 pub struct Message<'a> {
     pub channel_id: Cow<'a, str>,
     pub user_id: Cow<'a, str>,
     pub timestamp: u64,
     pub raw: Cow<'a, str>,
+    /// Numeric `MessageType`, 0 for other messages
+    #[serde(default)]
+    pub message_type: u8,
+    #[serde(default)]
+    pub login: Cow<'a, str>,
+    #[serde(default)]
+    pub display_name: Cow<'a, str>,
+    #[serde(default)]
+    pub message_id: Cow<'a, str>,
 }
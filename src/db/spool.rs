@@ -0,0 +1,260 @@
+use super::schema::Message;
+use anyhow::Context;
+use chrono::Utc;
+use lazy_static::lazy_static;
+use prometheus::{register_int_counter, register_int_gauge, IntCounter, IntGauge};
+use std::{
+    fs::{self, File},
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+use tracing::{debug, info};
+
+const SPOOL_FILE_EXTENSION: &str = "ndjson";
+const TEMP_FILE_EXTENSION: &str = "tmp";
+const CORRUPT_FILE_EXTENSION: &str = "corrupt";
+
+lazy_static! {
+    static ref SPOOLED_BYTES_GAUGE: IntGauge = register_int_gauge!(
+        "rustlog_writer_spooled_bytes",
+        "How many bytes of messages are waiting in the writer spool"
+    )
+    .unwrap();
+    static ref SPOOLED_BATCHES_GAUGE: IntGauge = register_int_gauge!(
+        "rustlog_writer_spooled_batches",
+        "How many batches are waiting in the writer spool"
+    )
+    .unwrap();
+    static ref REPLAYED_BATCHES_COUNTER: IntCounter = register_int_counter!(
+        "rustlog_writer_spool_replayed_batches",
+        "How many spooled batches have been written to the database"
+    )
+    .unwrap();
+    static ref REPLAYED_MESSAGES_COUNTER: IntCounter = register_int_counter!(
+        "rustlog_writer_spool_replayed_messages",
+        "How many spooled messages have been written to the database"
+    )
+    .unwrap();
+}
+
+/// Append-only directory of message batches which could not be written to the database.
+/// Every batch is stored in a separate NDJSON file, named so that sorting by name gives the write order.
+/// All methods do blocking file IO.
+#[derive(Clone)]
+pub struct Spool {
+    dir: PathBuf,
+    sequence: Arc<AtomicU64>,
+}
+
+impl Spool {
+    pub fn open(dir: impl Into<PathBuf>) -> anyhow::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)
+            .with_context(|| format!("Could not create spool directory {dir:?}"))?;
+
+        let spool = Self {
+            dir,
+            sequence: Arc::default(),
+        };
+
+        let pending = spool.pending()?;
+        let mut total_bytes = 0;
+        for path in &pending {
+            total_bytes += fs::metadata(path)?.len();
+        }
+        SPOOLED_BYTES_GAUGE.set(total_bytes.try_into().unwrap());
+        SPOOLED_BATCHES_GAUGE.set(pending.len().try_into().unwrap());
+
+        if !pending.is_empty() {
+            info!(
+                "Found {} spooled batches ({total_bytes} bytes) in {:?}",
+                pending.len(),
+                spool.dir
+            );
+        }
+
+        Ok(spool)
+    }
+
+    pub fn write(&self, messages: &[Message<'_>]) -> anyhow::Result<()> {
+        let name = format!(
+            "{:020}-{:010}",
+            Utc::now().timestamp_millis(),
+            self.sequence.fetch_add(1, Ordering::Relaxed)
+        );
+
+        // Write to a temporary file first so a crash never leaves a partial batch behind
+        let temp_path = self.dir.join(&name).with_extension(TEMP_FILE_EXTENSION);
+        let path = self.dir.join(&name).with_extension(SPOOL_FILE_EXTENSION);
+
+        let mut writer = BufWriter::new(File::create(&temp_path)?);
+        for message in messages {
+            serde_json::to_writer(&mut writer, message)?;
+            writer.write_all(b"\n")?;
+        }
+        let file = writer.into_inner().context("Could not flush spool file")?;
+        file.sync_all()?;
+        let len = file.metadata()?.len();
+        fs::rename(&temp_path, &path)?;
+
+        SPOOLED_BYTES_GAUGE.add(len.try_into().unwrap());
+        SPOOLED_BATCHES_GAUGE.inc();
+        debug!("Spooled {} messages to {path:?}", messages.len());
+
+        Ok(())
+    }
+
+    /// Spooled batch files, oldest first
+    pub fn pending(&self) -> anyhow::Result<Vec<PathBuf>> {
+        let mut paths = Vec::new();
+
+        for entry in fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) == Some(SPOOL_FILE_EXTENSION) {
+                paths.push(path);
+            }
+        }
+        paths.sort();
+
+        Ok(paths)
+    }
+
+    pub fn read(&self, path: &Path) -> anyhow::Result<Vec<Message<'static>>> {
+        let reader = BufReader::new(File::open(path)?);
+
+        reader
+            .lines()
+            .map(|line| {
+                let line = line?;
+                serde_json::from_str(&line).context("Invalid spooled message")
+            })
+            .collect()
+    }
+
+    pub fn remove(&self, path: &Path, message_count: usize) -> anyhow::Result<()> {
+        let len = fs::metadata(path)?.len();
+        fs::remove_file(path)?;
+
+        SPOOLED_BYTES_GAUGE.sub(len.try_into().unwrap());
+        SPOOLED_BATCHES_GAUGE.dec();
+        REPLAYED_BATCHES_COUNTER.inc();
+        REPLAYED_MESSAGES_COUNTER.inc_by(message_count.try_into().unwrap());
+
+        Ok(())
+    }
+
+    /// Renames a batch which cannot be read, so that it no longer blocks the batches after it
+    pub fn quarantine(&self, path: &Path) -> anyhow::Result<PathBuf> {
+        let len = fs::metadata(path)?.len();
+        let corrupt_path = path.with_extension(CORRUPT_FILE_EXTENSION);
+        fs::rename(path, &corrupt_path)?;
+
+        SPOOLED_BYTES_GAUGE.sub(len.try_into().unwrap());
+        SPOOLED_BATCHES_GAUGE.dec();
+
+        Ok(corrupt_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Spool;
+    use crate::db::schema::Message;
+    use pretty_assertions::assert_eq;
+    use std::{borrow::Cow, fs, path::PathBuf};
+
+    fn spool_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("rustlog-spool-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn message(raw: &'static str) -> Message<'static> {
+        Message {
+            channel_id: Cow::Borrowed("1"),
+            user_id: Cow::Borrowed("2"),
+            timestamp: 1642720582342,
+            raw: Cow::Borrowed(raw),
+            message_type: 1,
+            login: Cow::Borrowed("foo"),
+            display_name: Cow::Borrowed("Foo"),
+            message_id: Cow::Borrowed("abc-123"),
+        }
+    }
+
+    #[test]
+    fn write_and_read_batch() {
+        let dir = spool_dir("round-trip");
+        let spool = Spool::open(&dir).unwrap();
+
+        spool
+            .write(&[message("PRIVMSG #foo :hi"), message("PRIVMSG #foo :bye")])
+            .unwrap();
+
+        let pending = spool.pending().unwrap();
+        assert_eq!(pending.len(), 1);
+
+        let messages = spool.read(&pending[0]).unwrap();
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].raw, "PRIVMSG #foo :hi");
+        assert_eq!(messages[1].raw, "PRIVMSG #foo :bye");
+        assert_eq!(messages[0].channel_id, "1");
+        assert_eq!(messages[0].user_id, "2");
+        assert_eq!(messages[0].timestamp, 1642720582342);
+        assert_eq!(messages[0].message_type, 1);
+        assert_eq!(messages[0].login, "foo");
+        assert_eq!(messages[0].display_name, "Foo");
+        assert_eq!(messages[0].message_id, "abc-123");
+
+        spool.remove(&pending[0], messages.len()).unwrap();
+        assert!(spool.pending().unwrap().is_empty());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn pending_batches_are_in_write_order() {
+        let dir = spool_dir("ordering");
+        let spool = Spool::open(&dir).unwrap();
+
+        for raw in ["first", "second", "third"] {
+            spool.write(&[message(raw)]).unwrap();
+        }
+
+        let batches: Vec<String> = spool
+            .pending()
+            .unwrap()
+            .iter()
+            .map(|path| spool.read(path).unwrap().remove(0).raw.into_owned())
+            .collect();
+        assert_eq!(batches, vec!["first", "second", "third"]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn quarantine_corrupt_batch() {
+        let dir = spool_dir("corrupt");
+        let spool = Spool::open(&dir).unwrap();
+
+        spool.write(&[message("first")]).unwrap();
+        spool.write(&[message("second")]).unwrap();
+
+        let pending = spool.pending().unwrap();
+        fs::write(&pending[0], "not json\n").unwrap();
+
+        assert!(spool.read(&pending[0]).is_err());
+        let corrupt_path = spool.quarantine(&pending[0]).unwrap();
+        assert!(corrupt_path.exists());
+
+        let pending = spool.pending().unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(spool.read(&pending[0]).unwrap()[0].raw, "second");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
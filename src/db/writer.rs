@@ -1,21 +1,26 @@
-use super::schema::Message;
-use crate::{db::schema::MESSAGES_TABLE, ShutdownRx};
+use super::{schema::Message, spool::Spool};
+use crate::{config::Config, db::schema::MESSAGES_TABLE, ShutdownRx};
 use anyhow::{anyhow, Context};
+use arc_swap::ArcSwap;
 use clickhouse::Client;
 use lazy_static::lazy_static;
 use prometheus::{register_int_gauge, IntGauge};
-use std::time::{Duration, Instant};
+use std::{
+    mem,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 use tokio::{
     sync::mpsc::{channel, Sender},
-    task::JoinHandle,
-    time::sleep,
+    task::{spawn_blocking, JoinHandle},
+    time::{self, interval, sleep},
 };
-use tokio_stream::{wrappers::ReceiverStream, StreamExt};
-use tracing::{debug, error, info};
+use tracing::{debug, error, info, warn};
 
 const CHUNK_CAPACITY: usize = 750_000;
 const RETRY_COUNT: usize = 20;
 const RETRY_INTERVAL_SECONDS: u64 = 5;
+const SPOOL_REPLAY_INTERVAL_SECONDS: u64 = 30;
 
 lazy_static! {
     static ref BATCH_MSG_COUNT_GAGUE: IntGauge = register_int_gauge!(
@@ -28,31 +33,60 @@ lazy_static! {
 pub async fn create_writer(
     db: Client,
     mut shutdown_rx: ShutdownRx,
-    flush_interval: u64,
+    config: Arc<ArcSwap<Config>>,
+    spool_dir: Option<&str>,
 ) -> anyhow::Result<(Sender<Message<'static>>, JoinHandle<()>)> {
-    let (tx, rx) = channel(CHUNK_CAPACITY);
+    let spool = spool_dir
+        .map(Spool::open)
+        .transpose()
+        .context("Could not open writer spool")?;
 
-    let chunks_stream =
-        ReceiverStream::new(rx).chunks_timeout(CHUNK_CAPACITY, Duration::from_secs(flush_interval));
-    let mut chunks_stream = Box::pin(chunks_stream);
+    let (tx, mut rx) = channel(CHUNK_CAPACITY);
 
     let handle = tokio::spawn(async move {
+        let mut replay_interval = interval(Duration::from_secs(SPOOL_REPLAY_INTERVAL_SECONDS));
+
+        let mut chunk = Vec::new();
+        let mut flush_timer = Box::pin(sleep(Duration::ZERO));
+
         loop {
             tokio::select! {
-                Some(chunk) = chunks_stream.next() => {
-                    if let Err(err) = write_chunk_with_retry(&db, chunk).await {
-                        error!("Could not write messages: {err}");
+                Some(message) = rx.recv() => {
+                    // The interval is read for every chunk so that config reloads apply to the next one
+                    if chunk.is_empty() {
+                        let flush_interval =
+                            Duration::from_secs(config.load().clickhouse_flush_interval);
+                        flush_timer.as_mut().reset(time::Instant::now() + flush_interval);
+                    }
+                    chunk.push(message);
+
+                    if chunk.len() >= CHUNK_CAPACITY {
+                        flush_chunk(&db, spool.as_ref(), mem::take(&mut chunk)).await;
+                    }
+                }
+                () = &mut flush_timer, if !chunk.is_empty() => {
+                    flush_chunk(&db, spool.as_ref(), mem::take(&mut chunk)).await;
+                }
+                _ = replay_interval.tick(), if spool.is_some() => {
+                    if let Some(spool) = &spool {
+                        replay_spool(&db, spool).await;
                     }
                 }
                 Ok(()) = shutdown_rx.changed() => {
                     info!("Flushing database write buffer");
 
-                    while let Some(chunk) = chunks_stream.next().await {
-                        if let Err(err) = write_chunk_with_retry(&db, chunk).await {
-                            error!("Could not flush messages: {err}");
+                    while let Some(message) = rx.recv().await {
+                        chunk.push(message);
+
+                        if chunk.len() >= CHUNK_CAPACITY {
+                            flush_chunk(&db, spool.as_ref(), mem::take(&mut chunk)).await;
                         }
                     }
 
+                    if !chunk.is_empty() {
+                        flush_chunk(&db, spool.as_ref(), mem::take(&mut chunk)).await;
+                    }
+
                     break;
                 }
             }
@@ -62,9 +96,24 @@ pub async fn create_writer(
     Ok((tx, handle))
 }
 
-async fn write_chunk_with_retry(db: &Client, messages: Vec<Message<'_>>) -> anyhow::Result<()> {
+/// Writes the chunk and spools it right away if that fails, spooled chunks are retried by the replay.
+/// Without a spool, the write is retried for a while instead.
+async fn flush_chunk(db: &Client, spool: Option<&Spool>, chunk: Vec<Message<'static>>) {
+    let result = if spool.is_some() {
+        write_chunk(db, &chunk).await
+    } else {
+        write_chunk_with_retry(db, &chunk).await
+    };
+
+    if let Err(err) = result {
+        error!("Could not write messages: {err}");
+        spool_chunk(spool, chunk).await;
+    }
+}
+
+async fn write_chunk_with_retry(db: &Client, messages: &[Message<'_>]) -> anyhow::Result<()> {
     for attempt in 1..=RETRY_COUNT {
-        match write_chunk(db, &messages).await {
+        match write_chunk(db, messages).await {
             Ok(()) => {
                 if attempt > 1 {
                     debug!("Insert succeeded on attempt {attempt}");
@@ -82,6 +131,86 @@ async fn write_chunk_with_retry(db: &Client, messages: Vec<Message<'_>>) -> anyh
     ))
 }
 
+async fn spool_chunk(spool: Option<&Spool>, messages: Vec<Message<'static>>) {
+    let message_count = messages.len();
+
+    match spool {
+        Some(spool) => match run_blocking(spool, move |spool| spool.write(&messages)).await {
+            Ok(()) => info!("{message_count} messages have been spooled"),
+            Err(err) => {
+                error!("Could not spool messages, {message_count} messages have been lost: {err:#}")
+            }
+        },
+        None => error!("{message_count} messages have been lost"),
+    }
+}
+
+async fn replay_spool(db: &Client, spool: &Spool) {
+    let pending = match run_blocking(spool, |spool| spool.pending()).await {
+        Ok(pending) => pending,
+        Err(err) => {
+            error!("Could not list spooled batches: {err:#}");
+            return;
+        }
+    };
+
+    if pending.is_empty() {
+        return;
+    }
+    info!("Replaying {} spooled batches", pending.len());
+
+    for (i, path) in pending.iter().enumerate() {
+        let read_path = path.clone();
+        let messages = match run_blocking(spool, move |spool| spool.read(&read_path)).await {
+            Ok(messages) => messages,
+            Err(err) => {
+                error!("Could not read spooled batch {path:?}: {err:#}");
+
+                let corrupt_path = path.clone();
+                match run_blocking(spool, move |spool| spool.quarantine(&corrupt_path)).await {
+                    Ok(corrupt_path) => {
+                        warn!("Moved unreadable spooled batch to {corrupt_path:?}");
+                        continue;
+                    }
+                    Err(err) => {
+                        error!("Could not move unreadable batch {path:?}: {err:#}");
+                        return;
+                    }
+                }
+            }
+        };
+
+        if let Err(err) = write_chunk(db, &messages).await {
+            warn!("Could not replay spooled batch {path:?}, will try again later: {err}");
+            return;
+        }
+
+        let message_count = messages.len();
+        let removed_path = path.clone();
+        if let Err(err) = run_blocking(spool, move |spool| {
+            spool.remove(&removed_path, message_count)
+        })
+        .await
+        {
+            error!("Could not remove replayed batch {path:?}: {err:#}");
+            return;
+        }
+        debug!("Replayed spooled batch {}/{}", i + 1, pending.len());
+    }
+
+    info!("Finished replaying spooled batches");
+}
+
+/// Runs a spool operation on the blocking thread pool, as the spool does blocking file IO
+async fn run_blocking<T, F>(spool: &Spool, f: F) -> anyhow::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce(&Spool) -> anyhow::Result<T> + Send + 'static,
+{
+    let spool = spool.clone();
+    spawn_blocking(move || f(&spool)).await?
+}
+
 async fn write_chunk(db: &Client, messages: &[Message<'_>]) -> anyhow::Result<()> {
     let started_at = Instant::now();
 
@@ -0,0 +1,188 @@
+mod user;
+
+pub use user::{export_user, UserRef};
+
+use crate::{
+    db::{read_channel, read_stored_channel_ids},
+    error::Error,
+    logs::schema::ChannelLogDate,
+    migrator::reader::{get_day_path, COMPRESSED_CHANNEL_FILE},
+};
+use anyhow::{anyhow, Context};
+use chrono::{Datelike, NaiveDate};
+use flate2::{write::GzEncoder, Compression};
+use futures::{stream, StreamExt, TryStreamExt};
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Instant,
+};
+use tokio::{sync::mpsc, task};
+use tracing::{debug, info};
+
+const TEMP_FILE_EXTENSION: &str = "tmp";
+/// Lines buffered between reading from the database and the file writer
+const LINE_BUFFER_SIZE: usize = 1024;
+
+/// Writes logs from the database in the justlog directory layout,
+/// so they can be read back by [`crate::migrator::reader::LogsReader`]
+#[derive(Clone)]
+pub struct Exporter {
+    db: clickhouse::Client,
+    target_path: Arc<PathBuf>,
+    from: NaiveDate,
+    to: NaiveDate,
+}
+
+impl Exporter {
+    pub fn new(
+        db: clickhouse::Client,
+        target_path: String,
+        from: NaiveDate,
+        to: NaiveDate,
+    ) -> anyhow::Result<Self> {
+        if from > to {
+            return Err(anyhow!("`from` must not be later than `to`"));
+        }
+
+        let target_path = PathBuf::from(target_path);
+        fs::create_dir_all(&target_path)
+            .with_context(|| format!("Could not create target directory {target_path:?}"))?;
+
+        Ok(Self {
+            db,
+            target_path: Arc::new(target_path),
+            from,
+            to,
+        })
+    }
+
+    pub async fn run(self, channel_ids: Vec<String>, parallel_count: usize) -> anyhow::Result<()> {
+        let started_at = Instant::now();
+
+        let channel_ids = if channel_ids.is_empty() {
+            read_stored_channel_ids(&self.db).await?
+        } else {
+            channel_ids
+        };
+        let channel_count = channel_ids.len();
+
+        info!(
+            "Exporting {channel_count} channels from {} to {}",
+            self.from, self.to
+        );
+
+        let file_count: u64 = stream::iter(channel_ids)
+            .map(|channel_id| {
+                let exporter = self.clone();
+                async move {
+                    let count = exporter
+                        .export_channel(&channel_id)
+                        .await
+                        .with_context(|| format!("Could not export channel {channel_id}"))?;
+                    info!("Exported {count} days of channel {channel_id}");
+                    anyhow::Ok(count)
+                }
+            })
+            .buffer_unordered(parallel_count.max(1))
+            .try_fold(0, |total, count| async move { Ok(total + count) })
+            .await?;
+
+        info!(
+            "Export of {file_count} files finished in {:?}",
+            started_at.elapsed()
+        );
+
+        Ok(())
+    }
+
+    // Returns the number of written day files
+    async fn export_channel(&self, channel_id: &str) -> anyhow::Result<u64> {
+        let mut count = 0;
+
+        for date in self.from.iter_days().take_while(|date| *date <= self.to) {
+            let log_date = ChannelLogDate {
+                year: date.year() as u32,
+                month: date.month(),
+                day: date.day(),
+            };
+
+            let mut stream =
+                match read_channel(&self.db, channel_id, log_date, false, None, None).await {
+                    Ok(stream) => stream,
+                    Err(Error::NotFound) => continue,
+                    Err(err) => return Err(err.into()),
+                };
+
+            let day_path = get_day_path(&self.target_path, channel_id, date);
+            let path = day_path.join(COMPRESSED_CHANNEL_FILE);
+            let temp_path = path.with_extension(TEMP_FILE_EXTENSION);
+
+            // Compression and file writes are blocking, so they run on a separate thread
+            let (lines_tx, lines_rx) = mpsc::channel(LINE_BUFFER_SIZE);
+            let writer = task::spawn_blocking({
+                let temp_path = temp_path.clone();
+                move || write_compressed_lines(&day_path, &temp_path, lines_rx)
+            });
+
+            let mut lines = 0;
+            while let Some(line) = stream.next().await {
+                if lines_tx.send(line?).await.is_err() {
+                    // The writer has failed, its error is returned below
+                    break;
+                }
+                lines += 1;
+            }
+            drop(lines_tx);
+
+            let encoder = writer.await??;
+            task::spawn_blocking({
+                let path = path.clone();
+                move || finish_file(encoder, &temp_path, &path)
+            })
+            .await??;
+
+            debug!("Wrote {lines} lines to {path:?}");
+            count += 1;
+        }
+
+        Ok(count)
+    }
+}
+
+fn write_compressed_lines(
+    day_path: &Path,
+    temp_path: &Path,
+    mut lines_rx: mpsc::Receiver<String>,
+) -> anyhow::Result<GzEncoder<BufWriter<File>>> {
+    fs::create_dir_all(day_path)?;
+
+    let mut encoder = GzEncoder::new(
+        BufWriter::new(File::create(temp_path)?),
+        Compression::default(),
+    );
+    while let Some(line) = lines_rx.blocking_recv() {
+        encoder.write_all(line.as_bytes())?;
+        encoder.write_all(b"\n")?;
+    }
+
+    Ok(encoder)
+}
+
+// Only move the file into place once it is complete, so an interrupted export never leaves a truncated log
+fn finish_file(
+    encoder: GzEncoder<BufWriter<File>>,
+    temp_path: &Path,
+    path: &Path,
+) -> anyhow::Result<()> {
+    let file = encoder
+        .finish()?
+        .into_inner()
+        .context("Could not flush export file")?;
+    file.sync_all()?;
+    fs::rename(temp_path, path)?;
+
+    Ok(())
+}
@@ -0,0 +1,83 @@
+use super::TEMP_FILE_EXTENSION;
+use crate::{
+    db::read_user_id_by_previous_login, error::Error,
+    web::responders::user_export::UserExportResponse,
+};
+use anyhow::{anyhow, Context};
+use futures::StreamExt;
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+    time::Instant,
+};
+use tokio::{sync::mpsc, task};
+use tracing::info;
+
+/// Chunks buffered between reading from the database and the file writer
+const CHUNK_BUFFER_SIZE: usize = 64;
+
+/// A user given either by id or by a login they have used
+pub enum UserRef {
+    Id(String),
+    Login(String),
+}
+
+/// Writes everything stored about a user into a single NDJSON file, in the same format as the admin export endpoint
+pub async fn export_user(
+    db: &clickhouse::Client,
+    user: UserRef,
+    output_path: &Path,
+) -> anyhow::Result<()> {
+    let started_at = Instant::now();
+
+    let user_id = match user {
+        UserRef::Id(user_id) => user_id,
+        UserRef::Login(login) => read_user_id_by_previous_login(db, &login.to_lowercase())
+            .await?
+            .with_context(|| format!("No user with the login {login} has been logged"))?,
+    };
+
+    let export = match UserExportResponse::new(db, user_id.clone()).await {
+        Ok(export) => export,
+        Err(Error::NotFound) => return Err(anyhow!("No logs found for user {user_id}")),
+        Err(err) => return Err(err.into()),
+    };
+
+    // File writes are blocking, so they run on a separate thread
+    let (chunks_tx, chunks_rx) = mpsc::channel(CHUNK_BUFFER_SIZE);
+    let path = output_path.to_owned();
+    let writer = task::spawn_blocking(move || write_chunks(path, chunks_rx));
+
+    let mut stream = export.stream;
+    while let Some(chunk) = stream.next().await {
+        if chunks_tx.send(chunk?).await.is_err() {
+            // The writer has failed, its error is returned below
+            break;
+        }
+    }
+    drop(chunks_tx);
+    writer.await??;
+
+    info!(
+        "Exported user {user_id} to {output_path:?} in {:?}",
+        started_at.elapsed()
+    );
+
+    Ok(())
+}
+
+fn write_chunks(path: PathBuf, mut chunks_rx: mpsc::Receiver<Vec<u8>>) -> anyhow::Result<()> {
+    let temp_path = path.with_extension(TEMP_FILE_EXTENSION);
+    let mut writer = BufWriter::new(File::create(&temp_path)?);
+
+    while let Some(chunk) = chunks_rx.blocking_recv() {
+        writer.write_all(&chunk)?;
+    }
+
+    let file = writer.into_inner().context("Could not flush export file")?;
+    file.sync_all()?;
+    fs::rename(&temp_path, &path)?;
+
+    Ok(())
+}
@@ -1,5 +1,6 @@
-use twitch::Tag;
-use twitch_irc::message::IRCMessage;
+use super::schema::message::MessageType;
+use twitch::{Command, Tag};
+use twitch_irc::message::{IRCMessage, IRCPrefix};
 
 pub trait MessageWithTags {
     fn get_tag(&self, key: Tag) -> Option<&str>;
@@ -20,6 +21,55 @@ impl MessageWithTags for twitch::Message {
     }
 }
 
+pub trait MessageWithCommand: MessageWithTags {
+    fn message_type(&self) -> Option<MessageType>;
+
+    fn prefix_nick(&self) -> Option<&str>;
+
+    /// The last parameter after the channel name
+    fn trailing_param(&self) -> Option<&str>;
+}
+
+impl MessageWithCommand for IRCMessage {
+    fn message_type(&self) -> Option<MessageType> {
+        self.command.parse().ok()
+    }
+
+    fn prefix_nick(&self) -> Option<&str> {
+        match &self.prefix {
+            Some(IRCPrefix::Full { nick, .. }) => Some(nick.as_str()),
+            _ => None,
+        }
+    }
+
+    fn trailing_param(&self) -> Option<&str> {
+        self.params.get(1).map(String::as_str)
+    }
+}
+
+impl MessageWithCommand for twitch::Message {
+    fn message_type(&self) -> Option<MessageType> {
+        match self.command() {
+            Command::Privmsg => Some(MessageType::PrivMsg),
+            Command::Clearchat => Some(MessageType::ClearChat),
+            Command::UserNotice => Some(MessageType::UserNotice),
+            Command::Clearmsg => Some(MessageType::ClearMsg),
+            _ => None,
+        }
+    }
+
+    fn prefix_nick(&self) -> Option<&str> {
+        self.prefix().and_then(|prefix| prefix.nick)
+    }
+
+    fn trailing_param(&self) -> Option<&str> {
+        self.params().map(|params| {
+            let params = params.trim_start();
+            params.strip_prefix(':').unwrap_or(params)
+        })
+    }
+}
+
 pub fn extract_user_id<T: MessageWithTags>(msg: &T) -> Option<&str> {
     msg.get_tag(Tag::UserId)
         .or_else(|| msg.get_tag(Tag::TargetUserId))
@@ -38,3 +88,13 @@ pub fn extract_raw_timestamp<T: MessageWithTags>(msg: &T) -> Option<u64> {
     msg.get_tag(Tag::TmiSentTs)
         .and_then(|raw_timestamp| raw_timestamp.parse().ok())
 }
+
+/// Login of the user the message is associated with: the sender of chat messages and notices,
+/// or the target of moderation actions
+pub fn extract_login<T: MessageWithCommand>(msg: &T) -> Option<&str> {
+    match msg.message_type()? {
+        MessageType::PrivMsg => msg.prefix_nick(),
+        MessageType::ClearChat => msg.trailing_param(),
+        MessageType::UserNotice | MessageType::ClearMsg => msg.get_tag(Tag::Login),
+    }
+}
@@ -0,0 +1,105 @@
+use schemars::JsonSchema;
+use serde::Serialize;
+use std::borrow::Cow;
+
+const SUBSCRIPTION_BADGE_SETS: [&str; 2] = ["subscriber", "founder"];
+
+#[derive(Serialize, JsonSchema, Debug, PartialEq)]
+pub struct Badge<'a> {
+    /// Badge set, for example `subscriber`, `moderator` or `vip`
+    #[serde(rename = "setID")]
+    pub set_id: &'a str,
+    pub version: &'a str,
+}
+
+#[derive(Serialize, JsonSchema, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct BadgeInfo<'a> {
+    #[serde(rename = "setID")]
+    pub set_id: &'a str,
+    pub info: Cow<'a, str>,
+    /// Number of months the user has been subscribed for, only set for subscription badges
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub_months: Option<u32>,
+}
+
+/// Parses the `badges` tag (`set/version,set/version`)
+pub fn parse_badges(tag: &str) -> Vec<Badge> {
+    tag.split(',')
+        .filter_map(|badge| badge.split_once('/'))
+        .map(|(set_id, version)| Badge { set_id, version })
+        .collect()
+}
+
+/// Parses the `badge-info` tag (`set/info,set/info`)
+pub fn parse_badge_info(tag: &str) -> Vec<BadgeInfo> {
+    tag.split(',')
+        .filter_map(|badge| badge.split_once('/'))
+        .map(|(set_id, info)| {
+            let sub_months = if SUBSCRIPTION_BADGE_SETS.contains(&set_id) {
+                info.parse().ok()
+            } else {
+                None
+            };
+            let info = if info.contains('\\') {
+                Cow::Owned(twitch::unescape(info))
+            } else {
+                Cow::Borrowed(info)
+            };
+
+            BadgeInfo {
+                set_id,
+                info,
+                sub_months,
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_badge_info, parse_badges, Badge, BadgeInfo};
+    use pretty_assertions::assert_eq;
+    use std::borrow::Cow;
+
+    #[test]
+    fn parse_badge_list() {
+        assert_eq!(
+            parse_badges("moderator/1,subscriber/3012,premium/1"),
+            vec![
+                Badge {
+                    set_id: "moderator",
+                    version: "1"
+                },
+                Badge {
+                    set_id: "subscriber",
+                    version: "3012"
+                },
+                Badge {
+                    set_id: "premium",
+                    version: "1"
+                },
+            ]
+        );
+        assert_eq!(parse_badges(""), vec![]);
+    }
+
+    #[test]
+    fn parse_badge_info_list() {
+        assert_eq!(
+            parse_badge_info(r"subscriber/14,predictions/Yes\sor\sno"),
+            vec![
+                BadgeInfo {
+                    set_id: "subscriber",
+                    info: Cow::Borrowed("14"),
+                    sub_months: Some(14),
+                },
+                BadgeInfo {
+                    set_id: "predictions",
+                    info: Cow::Borrowed("Yes or no"),
+                    sub_months: None,
+                },
+            ]
+        );
+    }
+}
@@ -6,7 +6,11 @@ use serde::Serialize;
 use std::{borrow::Cow, collections::HashMap};
 use twitch::{Command, Tag};
 
-use super::ResponseMessage;
+use super::{
+    badge::{parse_badge_info, parse_badges, Badge, BadgeInfo},
+    emote::{parse_emotes, Emote},
+    ResponseMessage,
+};
 
 #[derive(Serialize, JsonSchema, Debug, PartialEq)]
 #[serde(rename_all = "camelCase")]
@@ -16,6 +20,13 @@ pub struct BasicMessage<'a> {
     #[schemars(with = "String")]
     pub timestamp: DateTime<Utc>,
     pub id: &'a str,
+    /// Id of the deleted message, only set for deleted messages
+    #[serde(rename = "targetMessageID", skip_serializing_if = "Option::is_none")]
+    pub target_message_id: Option<&'a str>,
+    /// Emotes in the text, ordered by their position
+    pub emotes: Vec<Emote<'a>>,
+    pub badges: Vec<Badge<'a>>,
+    pub badge_info: Vec<BadgeInfo<'a>>,
     pub tags: HashMap<&'a str, Cow<'a, str>>,
 }
 
@@ -38,6 +49,15 @@ impl<'a> ResponseMessage<'a> for BasicMessage<'a> {
             .map(|(key, value)| (key.as_str(), Cow::Borrowed(*value)))
             .collect();
 
+        let badges = irc_message
+            .tag(Tag::Badges)
+            .map(parse_badges)
+            .unwrap_or_default();
+        let badge_info = irc_message
+            .tag(Tag::BadgeInfo)
+            .map(parse_badge_info)
+            .unwrap_or_default();
+
         match irc_message.command() {
             Command::Privmsg => {
                 let raw_text = irc_message.params().context("Privmsg has no params")?;
@@ -47,12 +67,20 @@ impl<'a> ResponseMessage<'a> for BasicMessage<'a> {
                     .tag(Tag::DisplayName)
                     .context("Missing display name tag")?;
                 let id = irc_message.tag(Tag::Id).unwrap_or_default();
+                let emotes = irc_message
+                    .tag(Tag::Emotes)
+                    .map(|emotes_tag| parse_emotes(emotes_tag, text, 0))
+                    .unwrap_or_default();
 
                 Ok(Self {
                     text: Cow::Borrowed(text),
                     display_name,
                     timestamp,
                     id,
+                    target_message_id: None,
+                    emotes,
+                    badges,
+                    badge_info,
                     tags: response_tags,
                 })
             }
@@ -83,6 +111,31 @@ impl<'a> ResponseMessage<'a> for BasicMessage<'a> {
                     display_name: username.unwrap_or_default(),
                     timestamp,
                     id: "",
+                    target_message_id: None,
+                    emotes: vec![],
+                    badges,
+                    badge_info,
+                    tags: response_tags,
+                })
+            }
+            Command::Clearmsg => {
+                let login = irc_message.tag(Tag::Login).context("Missing login tag")?;
+                let deleted_text = irc_message
+                    .params()
+                    .map(extract_message_text)
+                    .unwrap_or_default();
+
+                Ok(Self {
+                    text: Cow::Owned(format!(
+                        "{login}'s message has been deleted: {deleted_text}"
+                    )),
+                    display_name: login,
+                    timestamp,
+                    id: "",
+                    target_message_id: irc_message.tag(Tag::TargetMsgId),
+                    emotes: vec![],
+                    badges,
+                    badge_info,
                     tags: response_tags,
                 })
             }
@@ -92,8 +145,17 @@ impl<'a> ResponseMessage<'a> for BasicMessage<'a> {
                     .context("System message tag missing")?;
                 let system_message = twitch::unescape(system_message);
 
+                let mut emotes = vec![];
+
                 let text = if let Some(user_message) = irc_message.params() {
                     let user_message = extract_message_text(user_message);
+
+                    // Emote positions refer to the user message, which follows the system message
+                    if let Some(emotes_tag) = irc_message.tag(Tag::Emotes) {
+                        let offset = system_message.chars().count() + 1;
+                        emotes = parse_emotes(emotes_tag, user_message, offset);
+                    }
+
                     Cow::Owned(format!("{system_message} {user_message}"))
                 } else {
                     Cow::Owned(system_message)
@@ -114,6 +176,10 @@ impl<'a> ResponseMessage<'a> for BasicMessage<'a> {
                     display_name,
                     timestamp,
                     id,
+                    target_message_id: None,
+                    emotes,
+                    badges,
+                    badge_info,
                     tags: response_tags,
                 })
             }
@@ -0,0 +1,110 @@
+use schemars::JsonSchema;
+use serde::Serialize;
+
+#[derive(Serialize, JsonSchema, Debug, PartialEq)]
+pub struct Emote<'a> {
+    pub id: &'a str,
+    /// The emote code as it appears in the message text
+    pub name: &'a str,
+    /// Position of the first code point of the emote in the message text
+    pub start: usize,
+    /// Position after the last code point of the emote in the message text
+    pub end: usize,
+}
+
+/// Parses the `emotes` tag (`id:start-end,start-end/id:start-end`) of a message.
+/// Positions in the tag refer to `text`, the returned positions are shifted by `offset` code points.
+pub fn parse_emotes<'a>(tag: &'a str, text: &'a str, offset: usize) -> Vec<Emote<'a>> {
+    let char_positions: Vec<usize> = text.char_indices().map(|(i, _)| i).collect();
+    let byte_position = |char_position: usize| {
+        if char_position == char_positions.len() {
+            Some(text.len())
+        } else {
+            char_positions.get(char_position).copied()
+        }
+    };
+
+    let mut emotes: Vec<Emote> = tag
+        .split('/')
+        .filter_map(|emote| emote.split_once(':'))
+        .flat_map(|(id, ranges)| {
+            ranges.split(',').filter_map(move |range| {
+                let (start, last) = range.split_once('-')?;
+                let start: usize = start.parse().ok()?;
+                // The tag contains the position of the last code point
+                let end = last.parse::<usize>().ok()? + 1;
+
+                let name = text.get(byte_position(start)?..byte_position(end)?)?;
+
+                Some(Emote {
+                    id,
+                    name,
+                    start: start + offset,
+                    end: end + offset,
+                })
+            })
+        })
+        .collect();
+
+    emotes.sort_by_key(|emote| emote.start);
+    emotes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_emotes, Emote};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn parse_multiple_emotes() {
+        let text = "Kappa Keepo Kappa";
+        let emotes = parse_emotes("25:0-4,12-16/1902:6-10", text, 0);
+
+        assert_eq!(
+            emotes,
+            vec![
+                Emote {
+                    id: "25",
+                    name: "Kappa",
+                    start: 0,
+                    end: 5
+                },
+                Emote {
+                    id: "1902",
+                    name: "Keepo",
+                    start: 6,
+                    end: 11
+                },
+                Emote {
+                    id: "25",
+                    name: "Kappa",
+                    start: 12,
+                    end: 17
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_emotes_after_multibyte_characters() {
+        let text = "héllo 👋 Kappa";
+        let emotes = parse_emotes("25:8-12", text, 3);
+
+        assert_eq!(
+            emotes,
+            vec![Emote {
+                id: "25",
+                name: "Kappa",
+                start: 11,
+                end: 16
+            }]
+        );
+    }
+
+    #[test]
+    fn skip_invalid_emotes() {
+        assert_eq!(parse_emotes("", "Kappa", 0), vec![]);
+        assert_eq!(parse_emotes("25:0-10", "Kappa", 0), vec![]);
+        assert_eq!(parse_emotes("25:a-b", "Kappa", 0), vec![]);
+    }
+}
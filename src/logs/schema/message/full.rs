@@ -4,7 +4,7 @@ use schemars::JsonSchema;
 use serde::Serialize;
 use serde_repr::Serialize_repr;
 use std::fmt::Display;
-use strum::EnumString;
+use strum::{AsRefStr, EnumIter, EnumString};
 use twitch::{Command, Tag};
 
 const TIMESTAMP_FORMAT: &str = "%Y-%m-%d %H:%M:%S";
@@ -21,7 +21,7 @@ pub struct FullMessage<'a> {
     pub r#type: MessageType,
 }
 
-#[derive(Serialize_repr, EnumString, Debug, PartialEq)]
+#[derive(Serialize_repr, EnumString, AsRefStr, EnumIter, Debug, PartialEq, Clone, Copy)]
 #[repr(i8)]
 #[strum(serialize_all = "UPPERCASE")]
 pub enum MessageType {
@@ -82,6 +82,17 @@ impl<'a> ResponseMessage<'a> for FullMessage<'a> {
                     r#type: MessageType::UserNotice,
                 })
             }
+            Command::Clearmsg => {
+                let username = irc_message.tag(Tag::Login).context("Missing login tag")?;
+
+                Ok(Self {
+                    basic,
+                    username,
+                    channel,
+                    raw: irc_message.raw(),
+                    r#type: MessageType::ClearMsg,
+                })
+            }
             other => Err(anyhow!("Unsupported message type: {other:?}")),
         }
     }
@@ -98,7 +109,8 @@ impl<'a> Display for FullMessage<'a> {
         let username = &self.username;
         let text = &self.basic.text;
 
-        if !username.is_empty() {
+        // The text of deletion notices already mentions the user
+        if !username.is_empty() && self.r#type != MessageType::ClearMsg {
             write!(f, "[{timestamp}] #{channel} {username}: {text}")
         } else {
             write!(f, "[{timestamp}] #{channel} {text}")
@@ -109,7 +121,11 @@ impl<'a> Display for FullMessage<'a> {
 #[cfg(test)]
 mod tests {
     use super::{FullMessage, MessageType};
-    use crate::logs::schema::message::{BasicMessage, ResponseMessage};
+    use crate::logs::schema::message::{
+        badge::{Badge, BadgeInfo},
+        emote::Emote,
+        BasicMessage, ResponseMessage,
+    };
     use chrono::{TimeZone, Utc};
     use pretty_assertions::assert_eq;
     use std::borrow::Cow;
@@ -127,6 +143,10 @@ mod tests {
                 display_name: "Snusbot",
                 timestamp: Utc.timestamp_millis_opt(1489263601000).unwrap(),
                 id: "",
+                target_message_id: None,
+                emotes: vec![],
+                badges: vec![],
+                badge_info: vec![],
                 tags: [
                     ("mod", "0"),
                     ("color", ""),
@@ -152,4 +172,89 @@ mod tests {
 
         assert_eq!(message, expected_message);
     }
+
+    #[test]
+    fn parse_clearmsg() {
+        let data = "@login=ronni;room-id=;target-msg-id=abc-123-def;tmi-sent-ts=1642720582342 :tmi.twitch.tv CLEARMSG #dallas :HeyGuys";
+        let irc_message = twitch::Message::parse(data).unwrap();
+        let message = FullMessage::from_irc_message(&irc_message).unwrap();
+        let expected_message = FullMessage {
+            basic: BasicMessage {
+                text: Cow::Borrowed("ronni's message has been deleted: HeyGuys"),
+                display_name: "ronni",
+                timestamp: Utc.timestamp_millis_opt(1642720582342).unwrap(),
+                id: "",
+                target_message_id: Some("abc-123-def"),
+                emotes: vec![],
+                badges: vec![],
+                badge_info: vec![],
+                tags: [
+                    ("login", "ronni"),
+                    ("room-id", ""),
+                    ("target-msg-id", "abc-123-def"),
+                    ("tmi-sent-ts", "1642720582342"),
+                ]
+                .into_iter()
+                .map(|(k, v)| (k, Cow::Borrowed(v)))
+                .collect(),
+            },
+            raw: data,
+            r#type: MessageType::ClearMsg,
+            username: "ronni",
+            channel: "dallas",
+        };
+
+        assert_eq!(message, expected_message);
+        assert_eq!(
+            message.to_string(),
+            "[2022-01-20 23:16:22] #dallas ronni's message has been deleted: HeyGuys"
+        );
+    }
+
+    #[test]
+    fn parse_usernotice_emotes() {
+        let data = r"@badge-info=subscriber/5;badges=subscriber/3;color=;display-name=Foo;emotes=25:0-4;id=abc-123;login=foo;mod=0;msg-id=resub;room-id=1;subscriber=1;system-msg=Foo\ssubscribed\sat\sTier\s1.;tmi-sent-ts=1642720582342;user-id=2;user-type= :tmi.twitch.tv USERNOTICE #dallas :Kappa hi";
+        let irc_message = twitch::Message::parse(data).unwrap();
+        let message = FullMessage::from_irc_message(&irc_message).unwrap();
+
+        assert_eq!(message.basic.text, "Foo subscribed at Tier 1. Kappa hi");
+        assert_eq!(
+            message.basic.emotes,
+            vec![Emote {
+                id: "25",
+                name: "Kappa",
+                start: 26,
+                end: 31,
+            }]
+        );
+    }
+
+    #[test]
+    fn parse_privmsg_badges() {
+        let data = r"@badge-info=subscriber/15;badges=moderator/1,subscriber/12;color=#0000FF;display-name=Foo;emotes=;id=abc-123;mod=1;room-id=1;subscriber=1;tmi-sent-ts=1642720582342;user-id=2;user-type=mod :foo!foo@foo.tmi.twitch.tv PRIVMSG #dallas :hi";
+        let irc_message = twitch::Message::parse(data).unwrap();
+        let message = FullMessage::from_irc_message(&irc_message).unwrap();
+
+        assert_eq!(
+            message.basic.badges,
+            vec![
+                Badge {
+                    set_id: "moderator",
+                    version: "1",
+                },
+                Badge {
+                    set_id: "subscriber",
+                    version: "12",
+                },
+            ]
+        );
+        assert_eq!(
+            message.basic.badge_info,
+            vec![BadgeInfo {
+                set_id: "subscriber",
+                info: Cow::Borrowed("15"),
+                sub_months: Some(15),
+            }]
+        );
+    }
 }
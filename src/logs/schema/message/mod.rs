@@ -1,4 +1,6 @@
+mod badge;
 mod basic;
+mod emote;
 mod full;
 
 pub use basic::BasicMessage;
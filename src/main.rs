@@ -4,6 +4,7 @@ mod bot;
 mod config;
 mod db;
 mod error;
+mod exporter;
 mod logs;
 mod migrator;
 mod web;
@@ -13,33 +14,43 @@ pub type ShutdownRx = watch::Receiver<()>;
 
 use anyhow::{anyhow, Context};
 use app::App;
-use args::{Args, Command};
+use arc_swap::ArcSwap;
+use args::{Args, Command, MigrateArgs};
+use bot::BotMessage;
+use chrono::NaiveDate;
 use clap::Parser;
-use config::Config;
-use db::{setup_db, writer::create_writer};
+use config::{AdminScope, Config};
+use db::{
+    delete_channel_logs, delete_late_channel_logs, retention::create_retention_task, setup_db,
+    wait_for_mutations, writer::create_writer,
+};
+use exporter::{Exporter, UserRef};
 use futures::{future::try_join_all, stream::FuturesUnordered, StreamExt};
-use migrator::Migrator;
+use migrator::{CheckpointSource, Migrator};
 use mimalloc::MiMalloc;
 use std::{
+    net::Ipv4Addr,
+    path::Path,
     sync::Arc,
     time::{Duration, Instant},
 };
 use tokio::{
     signal::unix::{signal, SignalKind},
-    sync::{mpsc, watch},
+    sync::{broadcast, mpsc, watch},
     time::timeout,
 };
-use tracing::{debug, info};
+use tracing::{debug, error, info, warn};
 use tracing_subscriber::EnvFilter;
 use twitch_api2::{
     twitch_oauth2::{AppAccessToken, Scope},
     HelixClient,
 };
-use twitch_irc::login::StaticLoginCredentials;
+use web::parse_listen_addr;
 
 use crate::app::cache::UsersCache;
 
 const SHUTDOWN_TIMEOUT_SECONDS: u64 = 8;
+const LIVE_MESSAGES_CAPACITY: usize = 10_000;
 
 #[global_allocator]
 static GLOBAL: MiMalloc = MiMalloc;
@@ -71,47 +82,64 @@ async fn main() -> anyhow::Result<()> {
 
     match args.subcommand {
         None => run(config, db).await,
-        Some(Command::Migrate {
-            source_dir,
+        Some(Command::Migrate(migrate_args)) => migrate(config, db, migrate_args).await,
+        Some(Command::Export {
+            target_dir,
             channel_id,
+            from,
+            to,
             jobs,
-        }) => migrate(db, source_dir, channel_id, jobs).await,
+        }) => export(db, target_dir, channel_id, from, to, jobs).await,
+        Some(Command::ExportUser {
+            user_id,
+            user,
+            output,
+        }) => {
+            let user = match (user_id, user) {
+                (Some(user_id), _) => UserRef::Id(user_id),
+                (None, Some(login)) => UserRef::Login(login),
+                (None, None) => return Err(anyhow!("Either a user id or a login is required")),
+            };
+            exporter::export_user(&db, user, Path::new(&output)).await
+        }
+        Some(Command::Purge {
+            channel_id,
+            from,
+            to,
+        }) => purge(config, db, channel_id, from, to).await,
     }
 }
 
 async fn run(config: Config, db: clickhouse::Client) -> anyhow::Result<()> {
+    bot::check_credentials(&config).await?;
+
     let mut shutdown_rx = listen_shutdown().await;
 
-    let helix_client: HelixClient<reqwest::Client> = HelixClient::default();
-    let token = generate_token(&config).await?;
+    let spool_dir = config.clickhouse_spool_dir.clone();
+    let app = create_app(config, db.clone()).await?;
 
     let (writer_tx, mut writer_handle) = create_writer(
         db.clone(),
         shutdown_rx.clone(),
-        config.clickhouse_flush_interval,
+        app.config.clone(),
+        spool_dir.as_deref(),
     )
     .await?;
 
-    let app = App {
-        helix_client,
-        token: Arc::new(token),
-        users: UsersCache::default(),
-        config: Arc::new(config),
-        db: Arc::new(db),
-        optout_codes: Arc::default(),
-    };
+    let mut retention_handle = create_retention_task(db, app.config.clone(), shutdown_rx.clone());
 
     let (bot_tx, bot_rx) = mpsc::channel(1);
+    listen_reload(app.clone(), bot_tx.clone());
+    let (live_tx, _) = broadcast::channel(LIVE_MESSAGES_CAPACITY);
 
-    let login_credentials = StaticLoginCredentials::anonymous();
     let mut bot_handle = tokio::spawn(bot::run(
-        login_credentials,
         app.clone(),
         writer_tx,
+        live_tx.clone(),
         shutdown_rx.clone(),
         bot_rx,
     ));
-    let mut web_handle = tokio::spawn(web::run(app, shutdown_rx.clone(), bot_tx));
+    let mut web_handle = tokio::spawn(web::run(app, shutdown_rx.clone(), bot_tx, live_tx));
 
     tokio::select! {
         _ = shutdown_rx.changed() => {
@@ -119,7 +147,8 @@ async fn run(config: Config, db: clickhouse::Client) -> anyhow::Result<()> {
 
             let started_at = Instant::now();
 
-            let shutdown_future = try_join_all([bot_handle, web_handle, writer_handle]);
+            let shutdown_future =
+                try_join_all([bot_handle, web_handle, writer_handle, retention_handle]);
             match timeout(Duration::from_secs(SHUTDOWN_TIMEOUT_SECONDS), shutdown_future).await {
                 Ok(Ok(_)) => {
                     debug!("Cleanup finished in {}ms", started_at.elapsed().as_millis());
@@ -141,17 +170,130 @@ async fn run(config: Config, db: clickhouse::Client) -> anyhow::Result<()> {
         _ = &mut writer_handle => {
             Err(anyhow!("Writer task exited unexpectedly"))
         }
+        _ = &mut retention_handle => {
+            Err(anyhow!("Retention task exited unexpectedly"))
+        }
+    }
+}
+
+async fn migrate(config: Config, db: clickhouse::Client, args: MigrateArgs) -> anyhow::Result<()> {
+    let source = args.format.open(&args.source_dir)?;
+    let app = if args.format.needs_user_lookup() {
+        Some(create_app(config, db.clone()).await?)
+    } else {
+        None
+    };
+    let checkpoint_mode = args.checkpoint_mode();
+    let checkpoint_source = CheckpointSource::new(args.format, &args.source_dir)?;
+
+    let migrator = Migrator::new(
+        db,
+        source,
+        app,
+        args.channel_id,
+        checkpoint_mode,
+        checkpoint_source,
+    )
+    .await?;
+
+    if args.dry_run {
+        migrator.dry_run(args.jobs).await
+    } else if args.verify {
+        migrator.verify(args.jobs).await
+    } else {
+        migrator.run(args.jobs).await
     }
 }
 
-async fn migrate(
+async fn export(
     db: clickhouse::Client,
-    source_logs_path: String,
+    target_path: String,
     channel_ids: Vec<String>,
+    from: NaiveDate,
+    to: NaiveDate,
     jobs: usize,
 ) -> anyhow::Result<()> {
-    let migrator = Migrator::new(db, source_logs_path, channel_ids).await?;
-    migrator.run(jobs).await
+    let exporter = Exporter::new(db, target_path, from, to)?;
+    exporter.run(channel_ids, jobs).await
+}
+
+async fn purge(
+    config: Config,
+    db: clickhouse::Client,
+    channel_id: String,
+    from: Option<NaiveDate>,
+    to: Option<NaiveDate>,
+) -> anyhow::Result<()> {
+    if from.zip(to).map_or(false, |(from, to)| from > to) {
+        return Err(anyhow!("The last day cannot be before the first day"));
+    }
+
+    let full_purge = from.is_none() && to.is_none();
+
+    if full_purge {
+        let removed = config.channels.write().unwrap().remove(&channel_id);
+        if removed {
+            config.save()?;
+            info!("Removed channel {channel_id} from the config");
+        }
+
+        match reload_running_instance(&config).await {
+            Ok(()) => info!("Reloaded the running instance"),
+            Err(err) => warn!("Could not reload the running instance, it keeps logging the channel until its config is reloaded: {err:#}"),
+        }
+    }
+
+    let mutation_keys = delete_channel_logs(&db, &channel_id, from, to).await?;
+    wait_for_mutations(&db, &mutation_keys).await?;
+
+    if full_purge {
+        info!("Deleting messages which were written while leaving the channel");
+        let flush_interval = Duration::from_secs(config.clickhouse_flush_interval);
+        let late_mutation_keys =
+            delete_late_channel_logs(&db, &channel_id, &mutation_keys, flush_interval).await?;
+        wait_for_mutations(&db, &late_mutation_keys).await?;
+    }
+
+    info!("Logs of channel {channel_id} have been deleted");
+    Ok(())
+}
+
+/// Asks a running instance to reload the config through the admin API, so it leaves removed channels
+async fn reload_running_instance(config: &Config) -> anyhow::Result<()> {
+    let key = config
+        .admin_key(AdminScope::Channels)
+        .context("No admin API key with the `channels` scope is configured")?;
+
+    let mut address = parse_listen_addr(&config.listen_address)?;
+    if address.ip().is_unspecified() {
+        address.set_ip(Ipv4Addr::LOCALHOST.into());
+    }
+
+    reqwest::Client::new()
+        .post(format!("http://{address}/admin/config/reload"))
+        .header("X-Api-Key", key)
+        .send()
+        .await?
+        .error_for_status()?;
+
+    Ok(())
+}
+
+async fn create_app(config: Config, db: clickhouse::Client) -> anyhow::Result<App> {
+    let helix_client: HelixClient<reqwest::Client> = HelixClient::default();
+    let token = generate_token(&config).await?;
+
+    Ok(App {
+        helix_client,
+        token: Arc::new(token),
+        users: UsersCache::default(),
+        config: Arc::new(ArcSwap::from_pointee(config)),
+        config_lock: Arc::default(),
+        purge_mutations: Arc::default(),
+        repeating_purges: Arc::default(),
+        db: Arc::new(db),
+        optout_codes: Arc::default(),
+    })
 }
 
 async fn generate_token(config: &Config) -> anyhow::Result<AppAccessToken> {
@@ -190,3 +332,16 @@ async fn listen_shutdown() -> watch::Receiver<()> {
 
     rx
 }
+
+fn listen_reload(app: App, bot_tx: mpsc::Sender<BotMessage>) {
+    let mut listener = signal(SignalKind::hangup()).unwrap();
+
+    tokio::spawn(async move {
+        while listener.recv().await.is_some() {
+            info!("Received reload signal");
+            if let Err(err) = app.reload_config(&bot_tx).await {
+                error!("Could not reload config: {err:#}");
+            }
+        }
+    });
+}
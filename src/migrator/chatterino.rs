@@ -0,0 +1,189 @@
+use super::{
+    reader::{get_channel_dirs, ChannelLogDateMap},
+    source::{ChatLine, LineContent, LogSource, SourceLine, SourceLines},
+};
+use crate::{error::Error, Result};
+use chrono::{Datelike, NaiveDate, NaiveTime, TimeZone, Utc};
+use std::{
+    collections::BTreeMap,
+    fs::{self, read_dir, File},
+    io::{BufRead, BufReader},
+    path::PathBuf,
+};
+use tracing::{debug, info};
+
+const FILE_EXTENSION: &str = ".log";
+const TIME_FORMAT: &str = "%H:%M:%S";
+
+/// Reads Chatterino logs, which are stored as `<channel>/<channel>-<year>-<month>-<day>.log`.
+/// Times in the logs have no timezone and are treated as UTC.
+pub struct ChatterinoReader {
+    root_path: PathBuf,
+}
+
+impl ChatterinoReader {
+    pub fn new(logs_path: &str) -> Result<Self> {
+        let root_path = PathBuf::from(logs_path);
+
+        if !root_path.exists() {
+            return Err(Error::NotFound);
+        }
+
+        Ok(Self { root_path })
+    }
+
+    fn get_day_file(&self, channel: &str, date: NaiveDate) -> PathBuf {
+        self.root_path.join(channel).join(format!(
+            "{channel}-{}{FILE_EXTENSION}",
+            date.format("%Y-%m-%d")
+        ))
+    }
+}
+
+impl LogSource for ChatterinoReader {
+    fn get_stored_channels(&self) -> Result<Vec<String>> {
+        get_channel_dirs(&self.root_path)
+    }
+
+    fn get_available_channel_logs(&self, channel: &str) -> Result<(ChannelLogDateMap, u64)> {
+        info!("Getting logs for channel {channel}");
+        let channel_path = self.root_path.join(channel);
+        if !channel_path.exists() {
+            return Err(Error::NotFound);
+        }
+
+        let mut logs = ChannelLogDateMap::new();
+        let mut total_size = 0;
+
+        for entry in read_dir(channel_path)? {
+            let entry = entry?;
+            let file_name = entry.file_name();
+
+            let date = file_name
+                .to_str()
+                .and_then(|name| name.strip_prefix(channel)?.strip_prefix('-'))
+                .and_then(|name| name.strip_suffix(FILE_EXTENSION))
+                .and_then(|date| NaiveDate::parse_from_str(date, "%Y-%m-%d").ok());
+
+            let metadata = entry.metadata()?;
+            match date {
+                Some(date) if metadata.is_file() => {
+                    total_size += metadata.len();
+                    logs.entry(date.year() as u32)
+                        .or_insert_with(BTreeMap::new)
+                        .entry(date.month())
+                        .or_insert_with(Vec::new)
+                        .push(date.day());
+                }
+                _ => debug!("Skipping unknown file {file_name:?}"),
+            }
+        }
+
+        for days in logs.values_mut().flat_map(|months| months.values_mut()) {
+            days.sort_unstable();
+        }
+
+        Ok((logs, total_size))
+    }
+
+    fn read_day(&self, channel: &str, date: NaiveDate) -> Result<SourceLines> {
+        let path = self.get_day_file(channel, date);
+        if !path.exists() {
+            return Err(Error::NotFound);
+        }
+        let reader = BufReader::new(File::open(path)?);
+
+        let lines = reader.lines().filter_map(move |line| {
+            let line = match line {
+                Ok(line) => line,
+                Err(err) => return Some(Err(err.into())),
+            };
+            // Headers such as `# Start logging at ...` and empty lines are not messages
+            if line.is_empty() || line.starts_with('#') {
+                return None;
+            }
+
+            let content = match parse_chat_line(date, &line) {
+                Some(chat_line) => LineContent::Chat(chat_line),
+                None => LineContent::Invalid(line.clone()),
+            };
+            Some(Ok(SourceLine {
+                len: line.len() + 1,
+                content,
+            }))
+        });
+
+        Ok(Box::new(lines))
+    }
+
+    fn channels_by_name(&self) -> bool {
+        true
+    }
+}
+
+/// Parses a line in the `[HH:MM:SS]  name: text` format.
+/// System messages such as timeouts have no sender and are not parsed.
+fn parse_chat_line(date: NaiveDate, line: &str) -> Option<ChatLine> {
+    let (time, message) = line.strip_prefix('[')?.split_once(']')?;
+    let time = NaiveTime::parse_from_str(time, TIME_FORMAT).ok()?;
+    let (sender, text) = message.trim_start().split_once(": ")?;
+
+    // Localized display names are followed by the login, e.g. `名前 (login)`
+    let (display_name, login) = match sender.split_once(" (") {
+        Some((display_name, login)) => (display_name, Some(login.strip_suffix(')')?)),
+        None => (sender, None),
+    };
+    if display_name.is_empty() || display_name.contains(' ') {
+        return None;
+    }
+
+    Some(ChatLine {
+        timestamp: Utc.from_utc_datetime(&date.and_time(time)),
+        login: login.map(str::to_owned),
+        display_name: display_name.to_owned(),
+        text: text.to_owned(),
+        tags: BTreeMap::new(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_chat_line;
+    use crate::migrator::source::ChatLine;
+    use chrono::{NaiveDate, TimeZone, Utc};
+    use pretty_assertions::assert_eq;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn parse_chat_message() {
+        let date = NaiveDate::from_ymd_opt(2023, 3, 14).unwrap();
+
+        assert_eq!(
+            parse_chat_line(date, "[12:34:56]  Someone: hello: world"),
+            Some(ChatLine {
+                timestamp: Utc.with_ymd_and_hms(2023, 3, 14, 12, 34, 56).unwrap(),
+                login: None,
+                display_name: "Someone".to_owned(),
+                text: "hello: world".to_owned(),
+                tags: BTreeMap::new(),
+            })
+        );
+
+        let localized = parse_chat_line(date, "[00:00:01]  名前 (namae): hi").unwrap();
+        assert_eq!(localized.display_name, "名前");
+        assert_eq!(localized.login.as_deref(), Some("namae"));
+    }
+
+    #[test]
+    fn skip_system_message() {
+        let date = NaiveDate::from_ymd_opt(2023, 3, 14).unwrap();
+
+        assert_eq!(
+            parse_chat_line(
+                date,
+                "[12:34:56]  someone has been timed out for 10m: reason"
+            ),
+            None
+        );
+    }
+}
@@ -0,0 +1,68 @@
+use super::source::SourceFormat;
+use anyhow::Context;
+use chrono::NaiveDate;
+use clap::ValueEnum;
+use clickhouse::Client;
+use std::{collections::HashSet, fs};
+
+/// The logs which checkpoints are recorded for
+pub struct CheckpointSource {
+    format: String,
+    path: String,
+}
+
+impl CheckpointSource {
+    pub fn new(format: SourceFormat, path: &str) -> anyhow::Result<Self> {
+        let format = format
+            .to_possible_value()
+            .expect("source formats are not skipped")
+            .get_name()
+            .to_owned();
+        // The same folder is recognized regardless of how its path is written
+        let path = fs::canonicalize(path)
+            .with_context(|| format!("Could not resolve source folder {path}"))?
+            .to_string_lossy()
+            .into_owned();
+
+        Ok(Self { format, path })
+    }
+}
+
+/// Days of a channel which have been fully written to the database by a previous migration from the same source
+pub async fn read_completed_days(
+    db: &Client,
+    source: &CheckpointSource,
+    channel_id: &str,
+) -> anyhow::Result<HashSet<NaiveDate>> {
+    // Checkpoints recorded before the source path was stored have an empty path
+    let dates: Vec<String> = db
+        .query(
+            "SELECT DISTINCT toString(date) FROM migration_checkpoint WHERE source_format = ? AND source_path IN (?, '') AND channel_id = ?",
+        )
+        .bind(&source.format)
+        .bind(&source.path)
+        .bind(channel_id)
+        .fetch_all()
+        .await?;
+
+    dates.into_iter().map(|date| Ok(date.parse()?)).collect()
+}
+
+pub async fn write_checkpoint(
+    db: &Client,
+    source: &CheckpointSource,
+    channel_id: &str,
+    date: NaiveDate,
+    lines: u64,
+) -> anyhow::Result<()> {
+    db.query("INSERT INTO migration_checkpoint (source_format, source_path, channel_id, date, lines) VALUES (?, ?, ?, toDate(?), ?)")
+        .bind(&source.format)
+        .bind(&source.path)
+        .bind(channel_id)
+        .bind(date.to_string())
+        .bind(lines)
+        .execute()
+        .await?;
+
+    Ok(())
+}
@@ -1,108 +1,172 @@
-mod reader;
-
-use self::reader::{LogsReader, COMPRESSED_CHANNEL_FILE, UNCOMPRESSED_CHANNEL_FILE};
+mod chatterino;
+mod checkpoint;
+mod ndjson;
+pub mod reader;
+mod report;
+pub mod source;
+
+pub use self::checkpoint::CheckpointSource;
+
+use self::{
+    checkpoint::{read_completed_days, write_checkpoint},
+    reader::log_dates,
+    report::{inspect_day, read_day_counts, LineReport},
+    source::{ChatLine, LineContent, LogSource},
+};
 use crate::{
+    app::App,
     db::schema::{Message, MESSAGES_TABLE},
-    logs::extract::{extract_raw_timestamp, extract_user_id},
+    logs::{
+        extract::{extract_login, extract_raw_timestamp, extract_user_id, MessageWithCommand},
+        schema::message::MessageType,
+    },
     migrator::reader::ChannelLogDateMap,
 };
-use anyhow::{anyhow, Context};
-use chrono::{DateTime, Datelike, TimeZone, Utc};
+use anyhow::{bail, Context};
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
 use clickhouse::inserter::Inserter;
-use flate2::bufread::GzDecoder;
-use indexmap::IndexMap;
+use futures::{stream, StreamExt};
 use std::{
     borrow::Cow,
+    collections::{HashMap, HashSet},
     convert::TryInto,
-    fs::File,
-    io::{BufRead, BufReader},
-    path::{Path, PathBuf},
+    fmt::Display,
     sync::{
         atomic::{AtomicU64, Ordering},
         Arc,
     },
     time::{Duration, Instant},
 };
-use tokio::sync::Semaphore;
+use tokio::{sync::Semaphore, task::spawn_blocking};
 use tracing::{debug, info, warn};
-use twitch::Command;
+use twitch::{Command, Tag};
 
 const INSERT_BATCH_SIZE: u64 = 10_000_000;
+const USER_LOOKUP_BATCH_SIZE: usize = 1000;
+
+/// How days recorded by a previous migration are treated
+///
+/// Days which are migrated again do not duplicate messages, as messages are deduplicated by their id
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CheckpointMode {
+    /// Skip days which have already been migrated
+    Resume,
+    /// Migrate all days again
+    Force,
+}
 
 #[derive(Clone)]
 pub struct Migrator {
     db: clickhouse::Client,
-    source_logs_path: String,
+    source: Arc<dyn LogSource>,
+    /// Used to look up ids and logins which are missing in the source
+    app: Option<App>,
     channel_ids: Arc<Vec<String>>,
+    checkpoint_mode: CheckpointMode,
+    checkpoint_source: Arc<CheckpointSource>,
+}
+
+/// A channel folder in the source and the channel it belongs to
+#[derive(Clone)]
+struct SourceChannel {
+    folder: String,
+    id: String,
+    login: Option<String>,
+}
+
+struct MigratedDay {
+    read_bytes: usize,
+    lines: u64,
 }
 
 impl Migrator {
     pub async fn new(
         db: clickhouse::Client,
-        source_logs_path: String,
+        source: Arc<dyn LogSource>,
+        app: Option<App>,
         channel_ids: Vec<String>,
+        checkpoint_mode: CheckpointMode,
+        checkpoint_source: CheckpointSource,
     ) -> anyhow::Result<Migrator> {
         Ok(Self {
             db,
-            source_logs_path,
+            source,
+            app,
             channel_ids: Arc::new(channel_ids),
+            checkpoint_mode,
+            checkpoint_source: Arc::new(checkpoint_source),
         })
     }
 
     pub async fn run(self, parallel_count: usize) -> anyhow::Result<()> {
-        let source_logs = LogsReader::new(&self.source_logs_path)?;
-
         let started_at = Instant::now();
-        let channels = source_logs.get_stored_channels().await?;
 
         let semaphore = Arc::new(Semaphore::new(parallel_count));
         let mut handles = Vec::with_capacity(parallel_count);
 
-        let filtered_channels: Vec<_> = channels
-            .into_iter()
-            .filter(|channel| self.channel_ids.is_empty() || self.channel_ids.contains(channel))
-            .collect();
+        let filtered_channels = self.resolve_channels().await?;
 
-        info!("Migrating channels {filtered_channels:?}");
+        info!(
+            "Migrating channels {:?}",
+            filtered_channels
+                .iter()
+                .map(|channel| &channel.id)
+                .collect::<Vec<_>>()
+        );
 
-        let mut channel_logs: IndexMap<String, ChannelLogDateMap> = IndexMap::new();
+        let mut channel_logs: Vec<(SourceChannel, ChannelLogDateMap)> = Vec::new();
 
         info!("Checking available logs");
 
         let mut total_bytes = 0;
 
-        for channel_id in filtered_channels {
-            let (available_logs, channel_bytes) =
-                source_logs.get_available_channel_logs(&channel_id)?;
+        for channel in filtered_channels {
+            let channel_id = &channel.id;
+            let (mut available_logs, channel_bytes) =
+                self.source.get_available_channel_logs(&channel.folder)?;
+
+            if self.checkpoint_mode == CheckpointMode::Resume {
+                let completed_days =
+                    read_completed_days(&self.db, &self.checkpoint_source, channel_id).await?;
+
+                if !completed_days.is_empty() {
+                    info!(
+                        "Skipping {} already migrated days of channel {channel_id}",
+                        completed_days.len()
+                    );
+                    remove_completed_days(&mut available_logs, &completed_days);
+                }
+            }
+
             total_bytes += channel_bytes;
-            channel_logs.insert(channel_id, available_logs);
+            channel_logs.push((channel, available_logs));
         }
 
         let channel_count = channel_logs.len();
         let total_mb = total_bytes / 1024 / 1024;
 
         info!("Migrating {channel_count} channels with {total_mb} MiB of logs");
-        info!("NOTE: the estimation numbers will be wrong if you use gzip compressed logs");
+        info!("NOTE: the estimation numbers will be wrong if you use gzip compressed logs or resume a migration");
 
         let mut i = 1;
 
         let total_read_bytes = Arc::new(AtomicU64::new(0));
         let migrated_percentage = Arc::new(AtomicU64::new(0));
 
-        for (channel_id, available_logs) in channel_logs {
-            info!("Reading channel {channel_id} ({i}/{channel_count})");
+        for (channel, available_logs) in channel_logs {
+            info!("Reading channel {} ({i}/{channel_count})", channel.id);
 
             for (year, months) in available_logs {
                 for (month, days) in months {
                     debug!("Waiting for free job slot");
                     let permit = semaphore.clone().acquire_owned().await.unwrap();
                     let migrator = self.clone();
-                    let channel_id = channel_id.clone();
-                    let root_path = source_logs.root_path.clone();
+                    let channel = channel.clone();
                     let total_read_bytes = total_read_bytes.clone();
                     let migrated_percentage = migrated_percentage.clone();
 
                     let handle = tokio::spawn(async move {
+                        let channel_id = &channel.id;
                         let mut inserter = migrator
                             .db
                             .inserter(MESSAGES_TABLE)?
@@ -115,18 +179,34 @@ impl Migrator {
 
                         info!("Migrating channel {channel_id} date {year}-{month}");
 
+                        // Days which have been written to the inserter, but not flushed to the database yet
+                        let mut pending_days = Vec::new();
+
                         for day in days {
                             let date = Utc
                                 .with_ymd_and_hms(year.try_into().unwrap(), month, day, 0, 0, 0)
                                 .unwrap();
-                            let day_bytes = migrator
-                                .migrate_day(&root_path, &channel_id, date, &mut inserter)
+                            let migrated_day = migrator
+                                .migrate_day(&channel, date, &mut inserter)
                                 .await
                                 .with_context(|| {
                                     format!("Could not migrate channel {channel_id} date {date}")
                                 })?;
+                            pending_days.push((date.date_naive(), migrated_day.lines));
+
+                            let stats = inserter.commit().await?;
+                            if stats.transactions > 0 {
+                                info!(
+                                    "DB: {} entries ({} transactions) have been inserted",
+                                    stats.entries, stats.transactions,
+                                );
+                                migrator
+                                    .write_checkpoints(channel_id, &mut pending_days)
+                                    .await?;
+                            }
 
-                            total_read_bytes.fetch_add(day_bytes as u64, Ordering::SeqCst);
+                            total_read_bytes
+                                .fetch_add(migrated_day.read_bytes as u64, Ordering::SeqCst);
                             let processed_bytes = total_read_bytes.load(Ordering::SeqCst);
 
                             let old_percentage = migrated_percentage.load(Ordering::SeqCst);
@@ -150,6 +230,9 @@ impl Migrator {
                                 stats.entries, stats.transactions,
                             );
                         }
+                        migrator
+                            .write_checkpoints(channel_id, &mut pending_days)
+                            .await?;
 
                         drop(permit);
                         Result::<_, anyhow::Error>::Ok(())
@@ -176,93 +259,370 @@ impl Migrator {
         Ok(())
     }
 
-    // Returns the number of read bytes
-    async fn migrate_day<'a>(
-        &self,
-        root_path: &Path,
-        channel_id: &'a str,
-        date: DateTime<Utc>,
-        inserter: &mut Inserter<Message<'a>>,
-    ) -> anyhow::Result<usize> {
-        let day_path = get_day_path(root_path, channel_id, date);
+    /// Parses all logs without writing anything and reports the problems that were found
+    pub async fn dry_run(self, parallel_count: usize) -> anyhow::Result<()> {
+        let channels = self.resolve_channels().await?;
+
+        let mut reports = stream::iter(channels)
+            .map(|channel| {
+                let source = self.source.clone();
+                spawn_blocking(move || {
+                    let (available_logs, _) = source.get_available_channel_logs(&channel.folder)?;
+
+                    let mut report = LineReport::default();
+                    for date in log_dates(&available_logs) {
+                        report +=
+                            inspect_day(&*source, &channel.folder, date).with_context(|| {
+                                format!("Could not read channel {} date {date}", channel.folder)
+                            })?;
+                    }
+                    anyhow::Ok((channel, report))
+                })
+            })
+            .buffered(parallel_count.max(1));
+
+        let mut total = LineReport::default();
+
+        while let Some(result) = reports.next().await {
+            let (channel, report) = result??;
+            info!("Channel {}: {report}", channel.id);
+            total += report;
+        }
 
-        let compressed_file_path = day_path.join(COMPRESSED_CHANNEL_FILE);
-        let uncompressed_file_path = day_path.join(UNCOMPRESSED_CHANNEL_FILE);
+        info!("Total: {total}");
 
-        if compressed_file_path.exists() {
-            debug!("Reading compressed log {compressed_file_path:?}");
-            let file_reader = BufReader::new(File::open(&compressed_file_path)?);
-            let gz = BufReader::new(GzDecoder::new(file_reader));
+        Ok(())
+    }
 
-            self.migrate_reader(gz, date, channel_id, inserter).await
-        } else if uncompressed_file_path.exists() {
-            debug!("Reading uncompressed log {uncompressed_file_path:?}");
-            let file_reader = BufReader::new(File::open(&uncompressed_file_path)?);
+    /// Compares the amount of valid lines in the logs with the amount of rows in the database for every day
+    pub async fn verify(self, parallel_count: usize) -> anyhow::Result<()> {
+        let channels = self.resolve_channels().await?;
 
-            self.migrate_reader(file_reader, date, channel_id, inserter)
-                .await
+        let mut results = stream::iter(channels)
+            .map(|channel| {
+                let source = self.source.clone();
+                let db = self.db.clone();
+                async move {
+                    let day_counts = read_day_counts(&db, &channel.id).await?;
+
+                    spawn_blocking(move || {
+                        let channel_id = &channel.id;
+                        let (available_logs, _) =
+                            source.get_available_channel_logs(&channel.folder)?;
+
+                        let mut days = 0;
+                        let mut mismatched_days = 0;
+
+                        for date in log_dates(&available_logs) {
+                            let report = inspect_day(&*source, &channel.folder, date)
+                                .with_context(|| {
+                                    format!("Could not read channel {channel_id} date {date}")
+                                })?;
+                            let db_count = day_counts.get(&date).copied().unwrap_or_default();
+
+                            days += 1;
+                            if report.valid() != db_count {
+                                warn!(
+                                    "Channel {channel_id} date {date}: {} valid lines in logs, {db_count} rows in the database",
+                                    report.valid()
+                                );
+                                mismatched_days += 1;
+                            }
+                        }
+
+                        info!("Channel {channel_id}: {mismatched_days}/{days} days do not match");
+                        anyhow::Ok(mismatched_days)
+                    })
+                    .await?
+                }
+            })
+            .buffered(parallel_count.max(1));
+
+        let mut total_mismatched_days = 0;
+        while let Some(result) = results.next().await {
+            total_mismatched_days += result?;
+        }
+
+        if total_mismatched_days > 0 {
+            bail!("Verification found {total_mismatched_days} days which do not match");
+        }
+        info!("Verification finished, all days match");
+
+        Ok(())
+    }
+
+    fn app(&self) -> anyhow::Result<&App> {
+        self.app
+            .as_ref()
+            .context("This log format requires looking up users with the Twitch API")
+    }
+
+    /// Maps the channel folders of the source to channel ids, filtered by the requested channels
+    async fn resolve_channels(&self) -> anyhow::Result<Vec<SourceChannel>> {
+        let folders = self.source.get_stored_channels()?;
+
+        let mut channels: Vec<SourceChannel> = if self.source.channels_by_name() {
+            let logins = folders.iter().map(|folder| folder.to_lowercase()).collect();
+            let ids: HashMap<String, String> = self
+                .app()?
+                .get_users(vec![], logins)
+                .await?
+                .into_iter()
+                .map(|(id, login)| (login, id))
+                .collect();
+
+            folders
+                .into_iter()
+                .filter_map(|folder| {
+                    let login = folder.to_lowercase();
+                    match ids.get(&login) {
+                        Some(id) => Some(SourceChannel {
+                            folder,
+                            id: id.clone(),
+                            login: Some(login),
+                        }),
+                        None => {
+                            warn!("Could not find the id of channel {folder}, skipping it");
+                            None
+                        }
+                    }
+                })
+                .collect()
         } else {
-            Err(anyhow!("File does not exist"))
+            folders
+                .into_iter()
+                .map(|folder| SourceChannel {
+                    id: folder.clone(),
+                    folder,
+                    login: None,
+                })
+                .collect()
+        };
+
+        channels.retain(|channel| {
+            self.channel_ids.is_empty()
+                || self.channel_ids.contains(&channel.id)
+                || self.channel_ids.contains(&channel.folder)
+        });
+
+        // Channel logins are needed to build messages for sources without raw IRC messages
+        if let Some(app) = &self.app {
+            let missing_ids: Vec<String> = channels
+                .iter()
+                .filter(|channel| channel.login.is_none())
+                .map(|channel| channel.id.clone())
+                .collect();
+
+            if !missing_ids.is_empty() {
+                let logins = app.get_users(missing_ids, vec![]).await?;
+                for channel in &mut channels {
+                    if channel.login.is_none() {
+                        channel.login = logins.get(&channel.id).cloned();
+                    }
+                }
+            }
         }
+
+        Ok(channels)
     }
 
-    async fn migrate_reader<'a, R: BufRead>(
+    async fn write_checkpoints(
         &self,
-        reader: R,
+        channel_id: &str,
+        days: &mut Vec<(NaiveDate, u64)>,
+    ) -> anyhow::Result<()> {
+        for (date, lines) in days.drain(..) {
+            write_checkpoint(&self.db, &self.checkpoint_source, channel_id, date, lines)
+                .await
+                .with_context(|| {
+                    format!("Could not save checkpoint for channel {channel_id} date {date}")
+                })?;
+        }
+        Ok(())
+    }
+
+    async fn migrate_day<'a>(
+        &self,
+        channel: &'a SourceChannel,
         datetime: DateTime<Utc>,
-        channel_id: &'a str,
         inserter: &mut Inserter<Message<'a>>,
-    ) -> anyhow::Result<usize> {
+    ) -> anyhow::Result<MigratedDay> {
+        let source_lines = self
+            .source
+            .read_day(&channel.folder, datetime.date_naive())?;
+
         let mut read_bytes = 0;
+        let mut lines = 0;
+        let mut user_ids = HashMap::new();
+        let mut chat_lines = Vec::with_capacity(USER_LOOKUP_BATCH_SIZE);
 
-        for (i, line) in reader.lines().enumerate() {
+        for (i, line) in source_lines.enumerate() {
             let line = line.with_context(|| format!("Could not read line {i} from input"))?;
-            read_bytes += line.len() + 1; // Add 1 byte for newline symbol
-            write_line(channel_id, line, inserter, datetime)
-                .await
-                .with_context(|| format!("Could not write line {i} to inserter"))?;
+            read_bytes += line.len;
+            lines += 1;
+
+            match line.content {
+                LineContent::Raw(raw) => {
+                    write_line(&channel.id, raw, inserter, datetime, &mut user_ids)
+                        .await
+                        .with_context(|| format!("Could not write line {i} to inserter"))?;
+                }
+                LineContent::Chat(chat_line) => {
+                    chat_lines.push(chat_line);
+                    if chat_lines.len() >= USER_LOOKUP_BATCH_SIZE {
+                        self.write_chat_lines(
+                            channel,
+                            &mut chat_lines,
+                            inserter,
+                            datetime,
+                            &mut user_ids,
+                        )
+                        .await?;
+                    }
+                }
+                LineContent::Invalid(line) => {
+                    warn!("Could not parse message `{line}`");
+                }
+            }
         }
 
-        let stats = inserter.commit().await?;
-        if stats.entries > 0 {
-            info!(
-                "DB: {} entries ({} transactions) have been inserted",
-                stats.entries, stats.transactions,
-            );
+        self.write_chat_lines(channel, &mut chat_lines, inserter, datetime, &mut user_ids)
+            .await?;
+
+        Ok(MigratedDay { read_bytes, lines })
+    }
+
+    /// Fills in the missing ids and logins of the messages with a single lookup and writes them
+    async fn write_chat_lines(
+        &self,
+        channel: &SourceChannel,
+        chat_lines: &mut Vec<ChatLine>,
+        inserter: &mut Inserter<Message<'_>>,
+        datetime: DateTime<Utc>,
+        user_ids: &mut HashMap<String, String>,
+    ) -> anyhow::Result<()> {
+        if chat_lines.is_empty() {
+            return Ok(());
         }
 
-        Ok(read_bytes)
+        let mut ids_to_request = HashSet::new();
+        let mut logins_to_request = HashSet::new();
+
+        for line in chat_lines.iter() {
+            match (line.user_id(), &line.login) {
+                (Some(_), Some(_)) => (),
+                (Some(user_id), None) => {
+                    ids_to_request.insert(user_id.to_owned());
+                }
+                (None, _) => logins_to_request.extend(line.guess_login()),
+            }
+        }
+
+        let users = self
+            .app()?
+            .get_users(
+                ids_to_request.into_iter().collect(),
+                logins_to_request.into_iter().collect(),
+            )
+            .await?;
+        let ids_by_login: HashMap<&str, &str> = users
+            .iter()
+            .map(|(id, login)| (login.as_str(), id.as_str()))
+            .collect();
+
+        let channel_login = channel.login.as_deref().unwrap_or(&channel.folder);
+
+        for line in chat_lines.drain(..) {
+            let guessed_login = line.guess_login();
+
+            let user_id = line.user_id().map(str::to_owned).or_else(|| {
+                guessed_login
+                    .as_deref()
+                    .and_then(|login| ids_by_login.get(login))
+                    .map(|id| id.to_string())
+            });
+            let login = line
+                .login
+                .clone()
+                .or_else(|| user_id.as_ref().and_then(|id| users.get(id).cloned()))
+                .or(guessed_login);
+
+            match login {
+                Some(login) => {
+                    let raw = line.into_raw(&channel.id, channel_login, user_id.as_deref(), &login);
+                    write_line(&channel.id, raw, inserter, datetime, user_ids).await?;
+                }
+                None => warn!(
+                    "Could not find the login of {}, skipping message",
+                    line.display_name
+                ),
+            }
+        }
+
+        Ok(())
     }
 }
 
+fn parse_line(raw: String) -> Result<twitch::Message, impl Display> {
+    twitch::Message::parse_with_whitelist(
+        raw,
+        twitch::whitelist!(TmiSentTs, UserId, TargetUserId, DisplayName, Login, Id),
+    )
+}
+
+/// `user_ids` maps logins to user ids seen in the current file,
+/// which is needed because CLEARMSG only carries the login of the user
 async fn write_line<'a>(
     channel_id: &'a str,
     raw: String,
     inserter: &mut Inserter<Message<'_>>,
     datetime: DateTime<Utc>,
+    user_ids: &mut HashMap<String, String>,
 ) -> anyhow::Result<()> {
-    match twitch::Message::parse_with_whitelist(
-        raw,
-        twitch::whitelist!(TmiSentTs, UserId, TargetUserId),
-    ) {
+    match parse_line(raw) {
         Ok(irc_message) => {
             let timestamp = extract_raw_timestamp(&irc_message)
                 .unwrap_or_else(|| datetime.timestamp_millis() as u64);
-            let user_id = extract_user_id(&irc_message).unwrap_or_else(|| {
-                if *irc_message.command() == Command::Privmsg {
-                    warn!(
-                        "Could not extract user id from PRIVMSG, partially malformed message: `{}`",
-                        irc_message.raw()
-                    );
+            let login = extract_login(&irc_message);
+            let user_id = match extract_user_id(&irc_message) {
+                Some(user_id) => {
+                    if let Some(login) = login {
+                        if user_ids.get(login).map(String::as_str) != Some(user_id) {
+                            user_ids.insert(login.to_owned(), user_id.to_owned());
+                        }
+                    }
+                    Cow::Borrowed(user_id)
                 }
-                ""
-            });
+                None => {
+                    if *irc_message.command() == Command::Privmsg {
+                        warn!(
+                            "Could not extract user id from PRIVMSG, partially malformed message: `{}`",
+                            irc_message.raw()
+                        );
+                    }
+
+                    match (irc_message.message_type(), login) {
+                        (Some(MessageType::ClearMsg), Some(login)) => user_ids
+                            .get(login)
+                            .map(|user_id| Cow::Owned(user_id.clone()))
+                            .unwrap_or_default(),
+                        _ => Cow::Borrowed(""),
+                    }
+                }
+            };
 
             let message = Message {
                 channel_id: Cow::Borrowed(channel_id),
-                user_id: Cow::Borrowed(user_id),
+                user_id,
                 timestamp,
                 raw: Cow::Borrowed(irc_message.raw()),
+                message_type: irc_message
+                    .message_type()
+                    .map(|message_type| message_type as u8)
+                    .unwrap_or_default(),
+                login: Cow::Borrowed(login.unwrap_or_default()),
+                display_name: Cow::Borrowed(irc_message.tag(Tag::DisplayName).unwrap_or_default()),
+                message_id: Cow::Borrowed(irc_message.tag(Tag::Id).unwrap_or_default()),
             };
             // This is safe because despite the function signature,
             // `inserter.write` only uses the value for serialization at the time of the method call, and not later
@@ -277,10 +637,15 @@ async fn write_line<'a>(
     Ok(())
 }
 
-fn get_day_path(root_path: &Path, channel_id: &str, date: DateTime<Utc>) -> PathBuf {
-    root_path
-        .join(channel_id)
-        .join(date.year().to_string())
-        .join(date.month().to_string())
-        .join(date.day().to_string())
+fn remove_completed_days(logs: &mut ChannelLogDateMap, completed_days: &HashSet<NaiveDate>) {
+    for (year, months) in logs.iter_mut() {
+        for (month, days) in months.iter_mut() {
+            days.retain(|day| {
+                NaiveDate::from_ymd_opt(*year as i32, *month, *day)
+                    .map_or(true, |date| !completed_days.contains(&date))
+            });
+        }
+        months.retain(|_, days| !days.is_empty());
+    }
+    logs.retain(|_, months| !months.is_empty());
 }
@@ -0,0 +1,93 @@
+use super::{
+    reader::{get_available_day_files, get_channel_dirs, get_day_path, ChannelLogDateMap},
+    source::{ChatLine, LineContent, LogSource, SourceLine, SourceLines},
+};
+use crate::{error::Error, Result};
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufRead, BufReader},
+    path::PathBuf,
+};
+use tracing::info;
+
+pub const NDJSON_CHANNEL_FILE: &str = "channel.ndjson";
+
+/// Tags which are only present on messages other than PRIVMSG
+const NON_CHAT_TAGS: [&str; 4] = ["msg-id", "target-msg-id", "target-user-id", "ban-duration"];
+
+/// Reads logs returned by rustlog with the `ndjson` parameter,
+/// stored as `<channel id>/<year>/<month>/<day>/channel.ndjson`
+pub struct NdJsonReader {
+    root_path: PathBuf,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NdJsonMessage {
+    raw: Option<String>,
+    text: String,
+    display_name: String,
+    timestamp: DateTime<Utc>,
+    #[serde(default)]
+    tags: BTreeMap<String, String>,
+}
+
+impl NdJsonReader {
+    pub fn new(logs_path: &str) -> Result<Self> {
+        let root_path = PathBuf::from(logs_path);
+
+        if !root_path.exists() {
+            return Err(Error::NotFound);
+        }
+
+        Ok(Self { root_path })
+    }
+}
+
+impl LogSource for NdJsonReader {
+    fn get_stored_channels(&self) -> Result<Vec<String>> {
+        get_channel_dirs(&self.root_path)
+    }
+
+    fn get_available_channel_logs(&self, channel_id: &str) -> Result<(ChannelLogDateMap, u64)> {
+        info!("Getting logs for channel {channel_id}");
+        get_available_day_files(&self.root_path.join(channel_id), &[NDJSON_CHANNEL_FILE])
+    }
+
+    fn read_day(&self, channel_id: &str, date: NaiveDate) -> Result<SourceLines> {
+        let path = get_day_path(&self.root_path, channel_id, date).join(NDJSON_CHANNEL_FILE);
+        if !path.exists() {
+            return Err(Error::NotFound);
+        }
+        let reader = BufReader::new(File::open(path)?);
+
+        Ok(Box::new(reader.lines().map(|line| {
+            let line = line?;
+            let len = line.len() + 1;
+
+            let content = match serde_json::from_str::<NdJsonMessage>(&line) {
+                Ok(NdJsonMessage { raw: Some(raw), .. }) => LineContent::Raw(raw),
+                Ok(message)
+                    if !NON_CHAT_TAGS
+                        .iter()
+                        .any(|tag| message.tags.contains_key(*tag)) =>
+                {
+                    LineContent::Chat(ChatLine {
+                        timestamp: message.timestamp,
+                        login: message.tags.get("login").cloned(),
+                        display_name: message.display_name,
+                        text: message.text,
+                        tags: message.tags,
+                    })
+                }
+                // Reconstructing other message types from the rendered text is not supported
+                _ => LineContent::Invalid(line),
+            };
+
+            Ok(SourceLine { len, content })
+        })))
+    }
+}
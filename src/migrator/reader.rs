@@ -1,11 +1,15 @@
+use super::source::{LineContent, LogSource, SourceLine, SourceLines};
 use crate::{error::Error, Result};
+use chrono::{Datelike, NaiveDate};
+use flate2::bufread::GzDecoder;
 use std::{
     collections::BTreeMap,
-    fs::{self, read_dir},
-    path::PathBuf,
+    fs::{self, read_dir, File},
+    io::{BufRead, BufReader},
+    path::{Path, PathBuf},
     sync::Arc,
 };
-use tracing::info;
+use tracing::{debug, info};
 
 pub const COMPRESSED_CHANNEL_FILE: &str = "channel.txt.gz";
 pub const UNCOMPRESSED_CHANNEL_FILE: &str = "channel.txt";
@@ -29,83 +33,154 @@ impl LogsReader {
             root_path: Arc::new(root_folder),
         })
     }
+}
 
-    pub async fn get_stored_channels(&self) -> Result<Vec<String>> {
-        let entries = read_dir(&*self.root_path)?;
-
-        let mut channels = Vec::new();
-        for entry in entries {
-            let entry = entry?;
-            if entry.metadata()?.is_dir() {
-                let channel = entry
-                    .file_name()
-                    .into_string()
-                    .expect("invalid channel folder name");
-                channels.push(channel);
-            }
-        }
-
-        Ok(channels)
+impl LogSource for LogsReader {
+    fn get_stored_channels(&self) -> Result<Vec<String>> {
+        get_channel_dirs(&self.root_path)
     }
 
-    pub fn get_available_channel_logs(&self, channel_id: &str) -> Result<(ChannelLogDateMap, u64)> {
+    fn get_available_channel_logs(&self, channel_id: &str) -> Result<(ChannelLogDateMap, u64)> {
         info!("Getting logs for channel {channel_id}");
-        let channel_path = self.root_path.join(channel_id);
-        if !channel_path.exists() {
-            return Err(Error::NotFound);
+        get_available_day_files(
+            &self.root_path.join(channel_id),
+            &[UNCOMPRESSED_CHANNEL_FILE, COMPRESSED_CHANNEL_FILE],
+        )
+    }
+
+    fn read_day(&self, channel_id: &str, date: NaiveDate) -> Result<SourceLines> {
+        let reader = open_day_log(&self.root_path, channel_id, date)?;
+
+        Ok(Box::new(reader.lines().map(|line| {
+            let line = line?;
+            Ok(SourceLine {
+                len: line.len() + 1, // Add 1 byte for newline symbol
+                content: LineContent::Raw(line),
+            })
+        })))
+    }
+}
+
+/// Names of all directories in the root path, which are the channels stored in it
+pub fn get_channel_dirs(root_path: &Path) -> Result<Vec<String>> {
+    let entries = read_dir(root_path)?;
+
+    let mut channels = Vec::new();
+    for entry in entries {
+        let entry = entry?;
+        if entry.metadata()?.is_dir() {
+            let channel = entry
+                .file_name()
+                .into_string()
+                .expect("invalid channel folder name");
+            channels.push(channel);
         }
+    }
 
-        let channel_dir = read_dir(channel_path)?;
+    Ok(channels)
+}
+
+/// Walks a `<year>/<month>/<day>` tree and collects the days which contain any of the given files
+pub fn get_available_day_files(
+    channel_path: &Path,
+    file_names: &[&str],
+) -> Result<(ChannelLogDateMap, u64)> {
+    if !channel_path.exists() {
+        return Err(Error::NotFound);
+    }
 
-        let mut years = BTreeMap::new();
-        let mut total_size = 0;
+    let channel_dir = read_dir(channel_path)?;
 
-        for year_entry in channel_dir {
-            let year_entry = year_entry?;
+    let mut years = BTreeMap::new();
+    let mut total_size = 0;
 
-            if year_entry.metadata()?.is_dir() {
-                let mut months = BTreeMap::new();
+    for year_entry in channel_dir {
+        let year_entry = year_entry?;
 
-                for month in 1..=12u32 {
-                    let mut days = Vec::with_capacity(31);
+        if year_entry.metadata()?.is_dir() {
+            let mut months = BTreeMap::new();
 
-                    for day in 1..=31u32 {
-                        let day_path = year_entry
-                            .path()
-                            .join(month.to_string())
-                            .join(day.to_string());
+            for month in 1..=12u32 {
+                let mut days = Vec::with_capacity(31);
 
-                        let compressed_channel_file_path = day_path.join(COMPRESSED_CHANNEL_FILE);
-                        let uncompressed_channel_file_path =
-                            day_path.join(UNCOMPRESSED_CHANNEL_FILE);
+                for day in 1..=31u32 {
+                    let day_path = year_entry
+                        .path()
+                        .join(month.to_string())
+                        .join(day.to_string());
 
-                        if let Ok(metadata) = fs::metadata(uncompressed_channel_file_path)
-                            .or_else(|_| fs::metadata(compressed_channel_file_path))
-                        {
-                            if metadata.is_file() {
-                                total_size += metadata.len();
-                                days.push(day);
-                            }
+                    let metadata = file_names
+                        .iter()
+                        .find_map(|file_name| fs::metadata(day_path.join(file_name)).ok());
+
+                    if let Some(metadata) = metadata {
+                        if metadata.is_file() {
+                            total_size += metadata.len();
+                            days.push(day);
                         }
                     }
+                }
 
-                    if !days.is_empty() {
-                        months.insert(month, days);
-                    }
+                if !days.is_empty() {
+                    months.insert(month, days);
                 }
+            }
 
-                let year = year_entry
-                    .file_name()
-                    .to_str()
-                    .and_then(|name| name.parse().ok())
-                    .expect("invalid log entry year name");
+            let year = year_entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.parse().ok())
+                .expect("invalid log entry year name");
 
-                if !months.is_empty() {
-                    years.insert(year, months);
-                }
+            if !months.is_empty() {
+                years.insert(year, months);
             }
         }
+    }
 
-        Ok((years, total_size))
+    Ok((years, total_size))
+}
+
+/// Path of the directory holding the logs of a channel for the given day
+pub fn get_day_path(root_path: &Path, channel_id: &str, date: impl Datelike) -> PathBuf {
+    root_path
+        .join(channel_id)
+        .join(date.year().to_string())
+        .join(date.month().to_string())
+        .join(date.day().to_string())
+}
+
+/// Opens the log file of a channel for the given day, decompressing it if needed
+pub fn open_day_log(
+    root_path: &Path,
+    channel_id: &str,
+    date: impl Datelike,
+) -> Result<Box<dyn BufRead + Send>> {
+    let day_path = get_day_path(root_path, channel_id, date);
+
+    let compressed_file_path = day_path.join(COMPRESSED_CHANNEL_FILE);
+    let uncompressed_file_path = day_path.join(UNCOMPRESSED_CHANNEL_FILE);
+
+    if compressed_file_path.exists() {
+        debug!("Reading compressed log {compressed_file_path:?}");
+        let file_reader = BufReader::new(File::open(&compressed_file_path)?);
+        Ok(Box::new(BufReader::new(GzDecoder::new(file_reader))))
+    } else if uncompressed_file_path.exists() {
+        debug!("Reading uncompressed log {uncompressed_file_path:?}");
+        Ok(Box::new(BufReader::new(File::open(
+            &uncompressed_file_path,
+        )?)))
+    } else {
+        Err(Error::NotFound)
     }
 }
+
+/// All days in the map, in chronological order
+pub fn log_dates(logs: &ChannelLogDateMap) -> impl Iterator<Item = NaiveDate> + '_ {
+    logs.iter().flat_map(|(year, months)| {
+        months.iter().flat_map(move |(month, days)| {
+            days.iter()
+                .filter_map(move |day| NaiveDate::from_ymd_opt(*year as i32, *month, *day))
+        })
+    })
+}
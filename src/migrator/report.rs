@@ -0,0 +1,99 @@
+use super::{
+    parse_line,
+    source::{LineContent, LogSource},
+};
+use crate::logs::extract::extract_raw_timestamp;
+use chrono::NaiveDate;
+use clickhouse::{Client, Row};
+use serde::Deserialize;
+use std::{collections::HashMap, fmt::Display, ops::AddAssign};
+use tracing::debug;
+
+#[derive(Default, Debug, Clone, Copy)]
+pub struct LineReport {
+    pub lines: u64,
+    pub malformed: u64,
+    pub missing_timestamps: u64,
+}
+
+impl LineReport {
+    /// Lines which would be written to the database
+    pub fn valid(&self) -> u64 {
+        self.lines - self.malformed
+    }
+}
+
+impl AddAssign for LineReport {
+    fn add_assign(&mut self, other: Self) {
+        self.lines += other.lines;
+        self.malformed += other.malformed;
+        self.missing_timestamps += other.missing_timestamps;
+    }
+}
+
+impl Display for LineReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} lines, {} malformed, {} without a timestamp",
+            self.lines, self.malformed, self.missing_timestamps
+        )
+    }
+}
+
+/// Parses a day of logs the same way the migration does
+pub fn inspect_day(
+    source: &dyn LogSource,
+    channel: &str,
+    date: NaiveDate,
+) -> anyhow::Result<LineReport> {
+    let mut report = LineReport::default();
+
+    for line in source.read_day(channel, date)? {
+        report.lines += 1;
+
+        match line?.content {
+            LineContent::Raw(raw) => match parse_line(raw) {
+                Ok(irc_message) => {
+                    if extract_raw_timestamp(&irc_message).is_none() {
+                        report.missing_timestamps += 1;
+                    }
+                }
+                Err(msg) => {
+                    debug!("Malformed line in channel {channel} date {date}: `{msg}`");
+                    report.malformed += 1;
+                }
+            },
+            // Messages without raw IRC get their timestamp from the source
+            LineContent::Chat(_) => (),
+            LineContent::Invalid(line) => {
+                debug!("Malformed line in channel {channel} date {date}: `{line}`");
+                report.malformed += 1;
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// Row counts of a channel per UTC day, which is how the logs are split into files
+pub async fn read_day_counts(
+    db: &Client,
+    channel_id: &str,
+) -> anyhow::Result<HashMap<NaiveDate, u64>> {
+    #[derive(Row, Deserialize)]
+    struct DayCount {
+        date: String,
+        count: u64,
+    }
+
+    let rows = db
+        .query("SELECT toString(toDate(timestamp, 'UTC')) AS date, uniqExact(dedup_key) AS count FROM message WHERE channel_id = ? GROUP BY date")
+        .bind(channel_id)
+        .fetch_all::<DayCount>()
+        .await?;
+
+    rows.into_iter()
+        .map(|row| Ok((row.date.parse()?, row.count)))
+        .collect()
+}
@@ -0,0 +1,149 @@
+use super::{
+    chatterino::ChatterinoReader,
+    ndjson::NdJsonReader,
+    reader::{ChannelLogDateMap, LogsReader},
+};
+use crate::Result;
+use chrono::{DateTime, NaiveDate, Utc};
+use clap::ValueEnum;
+use std::{collections::BTreeMap, fmt::Write, sync::Arc};
+
+pub type SourceLines = Box<dyn Iterator<Item = Result<SourceLine>> + Send>;
+
+/// Logs stored on disk, split into channels and days
+pub trait LogSource: Send + Sync {
+    /// Names of the channel folders, which are either channel ids or logins depending on the format
+    fn get_stored_channels(&self) -> Result<Vec<String>>;
+
+    /// Available days of the channel and the total size of their files
+    fn get_available_channel_logs(&self, channel: &str) -> Result<(ChannelLogDateMap, u64)>;
+
+    fn read_day(&self, channel: &str, date: NaiveDate) -> Result<SourceLines>;
+
+    /// Whether channel folders are named after the channel login instead of its id
+    fn channels_by_name(&self) -> bool {
+        false
+    }
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum SourceFormat {
+    /// justlog `<channel id>/<year>/<month>/<day>/channel.txt(.gz)` tree
+    Justlog,
+    /// Chatterino `<channel>/<channel>-<year>-<month>-<day>.log` files
+    Chatterino,
+    /// rustlog NDJSON responses saved as `<channel id>/<year>/<month>/<day>/channel.ndjson`
+    Ndjson,
+}
+
+impl SourceFormat {
+    pub fn open(self, path: &str) -> Result<Arc<dyn LogSource>> {
+        Ok(match self {
+            SourceFormat::Justlog => Arc::new(LogsReader::new(path)?),
+            SourceFormat::Chatterino => Arc::new(ChatterinoReader::new(path)?),
+            SourceFormat::Ndjson => Arc::new(NdJsonReader::new(path)?),
+        })
+    }
+
+    /// Whether the logs lack ids or logins, which then have to be looked up with the Twitch API
+    pub fn needs_user_lookup(self) -> bool {
+        !matches!(self, SourceFormat::Justlog)
+    }
+}
+
+pub struct SourceLine {
+    /// Size of the line in the source file, used for progress estimation
+    pub len: usize,
+    pub content: LineContent,
+}
+
+pub enum LineContent {
+    /// Raw IRC message, stored as is
+    Raw(String),
+    /// Chat message without the full IRC message
+    Chat(ChatLine),
+    /// Line which could not be parsed
+    Invalid(String),
+}
+
+/// Chat message from a source that does not store raw IRC messages.
+/// It is turned into an IRC PRIVMSG once the missing ids and logins are known.
+#[derive(Debug, PartialEq)]
+pub struct ChatLine {
+    pub timestamp: DateTime<Utc>,
+    pub login: Option<String>,
+    pub display_name: String,
+    pub text: String,
+    /// Unescaped IRC tags
+    pub tags: BTreeMap<String, String>,
+}
+
+impl ChatLine {
+    pub fn user_id(&self) -> Option<&str> {
+        self.tags
+            .get("user-id")
+            .map(String::as_str)
+            .filter(|user_id| !user_id.is_empty())
+    }
+
+    /// The login if it is known, otherwise the display name if it can be used as a login
+    pub fn guess_login(&self) -> Option<String> {
+        self.login.clone().or_else(|| {
+            let login = self.display_name.to_lowercase();
+            login
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_')
+                .then_some(login)
+        })
+    }
+
+    pub fn into_raw(
+        mut self,
+        channel_id: &str,
+        channel_login: &str,
+        user_id: Option<&str>,
+        login: &str,
+    ) -> String {
+        let timestamp = self.timestamp.timestamp_millis().to_string();
+        let tags = &mut self.tags;
+        tags.entry("display-name".to_owned())
+            .or_insert(self.display_name);
+        tags.entry("room-id".to_owned())
+            .or_insert_with(|| channel_id.to_owned());
+        tags.entry("tmi-sent-ts".to_owned()).or_insert(timestamp);
+        if let Some(user_id) = user_id {
+            tags.insert("user-id".to_owned(), user_id.to_owned());
+        }
+
+        let mut raw = String::from("@");
+        for (i, (key, value)) in self.tags.iter().enumerate() {
+            if i > 0 {
+                raw.push(';');
+            }
+            write!(raw, "{key}={}", escape_tag_value(value)).unwrap();
+        }
+        write!(
+            raw,
+            " :{login}!{login}@{login}.tmi.twitch.tv PRIVMSG #{channel_login} :{}",
+            self.text.replace(['\r', '\n'], " ")
+        )
+        .unwrap();
+
+        raw
+    }
+}
+
+fn escape_tag_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            ';' => escaped.push_str("\\:"),
+            ' ' => escaped.push_str("\\s"),
+            '\r' => escaped.push_str("\\r"),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
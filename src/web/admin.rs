@@ -1,43 +1,57 @@
-use crate::{app::App, bot::BotMessage, error::Error};
+use super::{
+    handlers::no_cache_header,
+    responders::user_export::UserExportResponse,
+    schema::{ChannelParam, MutationProgress, UserParam},
+};
+use crate::{app::App, bot::BotMessage, config::AdminScope, error::Error};
 use aide::{
+    axum::IntoApiResponse,
     openapi::{
         HeaderStyle, Parameter, ParameterData, ParameterSchemaOrContent, ReferenceOr, SchemaObject,
     },
     transform::TransformOperation,
 };
 use axum::{
-    extract::State,
+    extract::{Query, State},
     http::Request,
     middleware::Next,
     response::{IntoResponse, Response},
     Extension, Json,
 };
+use chrono::NaiveDate;
 use reqwest::StatusCode;
 use schemars::JsonSchema;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use tokio::sync::mpsc::Sender;
+use tracing::Span;
 
 pub async fn admin_auth<B>(
-    app: State<App>,
+    State((app, scope)): State<(App, AdminScope)>,
     request: Request<B>,
     next: Next<B>,
 ) -> Result<Response, impl IntoResponse> {
-    if let Some(admin_key) = &app.config.admin_api_key {
-        if request
-            .headers()
-            .get("X-Api-Key")
-            .and_then(|value| value.to_str().ok())
-            == Some(admin_key)
-        {
-            let response = next.run(request).await;
-            return Ok(response);
-        }
+    let key_name = request
+        .headers()
+        .get("X-Api-Key")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|key| {
+            app.config
+                .load()
+                .authorize_admin_key(key, scope)
+                .map(str::to_owned)
+        });
+
+    if let Some(key_name) = key_name {
+        Span::current().record("admin.key", key_name.as_str());
+
+        let response = next.run(request).await;
+        return Ok(response);
     }
 
     Err((StatusCode::FORBIDDEN, "No, I don't think so"))
 }
 
-pub fn admin_auth_doc(op: &mut TransformOperation) {
+pub fn admin_auth_doc(op: &mut TransformOperation, scope: AdminScope) {
     let schema = aide::gen::in_context(|ctx| ctx.schema.subschema_for::<String>());
 
     op.inner_mut()
@@ -45,7 +59,10 @@ pub fn admin_auth_doc(op: &mut TransformOperation) {
         .push(ReferenceOr::Item(Parameter::Header {
             parameter_data: ParameterData {
                 name: "X-Api-Key".to_owned(),
-                description: Some("Configured admin API key".to_owned()),
+                description: Some(format!(
+                    "Configured admin API key with the `{}` scope",
+                    scope.as_ref()
+                )),
                 required: true,
                 deprecated: None,
                 format: ParameterSchemaOrContent::Schema(SchemaObject {
@@ -93,3 +110,197 @@ pub async fn remove_channels(
 
     Ok(())
 }
+
+#[derive(Deserialize, JsonSchema)]
+pub struct OptOutRequest {
+    #[serde(flatten)]
+    pub user: UserParam,
+    /// Limits the opt out to a single channel
+    #[serde(flatten)]
+    pub channel: Option<ChannelParam>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct OptOutList {
+    pub users: Vec<OptedOutUser>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct OptedOutUser {
+    #[serde(rename = "userID")]
+    pub user_id: String,
+    /// Channels the user opted out of, empty if the user opted out everywhere
+    #[serde(rename = "channelIDs")]
+    pub channel_ids: Vec<String>,
+}
+
+pub async fn list_optouts(app: State<App>) -> Json<OptOutList> {
+    let config = app.config.load();
+
+    let mut users: Vec<OptedOutUser> = config
+        .opt_out
+        .iter()
+        .map(|entry| OptedOutUser {
+            user_id: entry.key().clone(),
+            channel_ids: vec![],
+        })
+        .collect();
+
+    users.extend(config.channel_opt_out.iter().map(|entry| {
+        let mut channel_ids: Vec<String> = entry.value().iter().cloned().collect();
+        channel_ids.sort_unstable();
+
+        OptedOutUser {
+            user_id: entry.key().clone(),
+            channel_ids,
+        }
+    }));
+    users.sort_by(|a, b| a.user_id.cmp(&b.user_id));
+
+    Json(OptOutList { users })
+}
+
+pub async fn add_optout(app: State<App>, Json(request): Json<OptOutRequest>) -> Result<(), Error> {
+    let (user_id, channel_id) = resolve_optout_request(&app, request).await?;
+
+    app.optout_user(&user_id, channel_id.as_deref()).await?;
+
+    Ok(())
+}
+
+pub async fn remove_optout(
+    app: State<App>,
+    Json(request): Json<OptOutRequest>,
+) -> Result<(), Error> {
+    let (user_id, channel_id) = resolve_optout_request(&app, request).await?;
+
+    if app.remove_optout(&user_id, channel_id.as_deref()).await? {
+        Ok(())
+    } else {
+        Err(Error::NotFound)
+    }
+}
+
+async fn resolve_optout_request(
+    app: &App,
+    OptOutRequest { user, channel }: OptOutRequest,
+) -> Result<(String, Option<String>), Error> {
+    let user_id = resolve_user_id(app, user).await?;
+    let channel_id = match channel {
+        Some(channel) => Some(resolve_channel_id(app, channel).await?),
+        None => None,
+    };
+
+    Ok((user_id, channel_id))
+}
+
+async fn resolve_user_id(app: &App, user: UserParam) -> Result<String, Error> {
+    match user {
+        UserParam::UserId(id) => Ok(id),
+        UserParam::User(name) => app.get_user_id_by_name(&name).await,
+    }
+}
+
+async fn resolve_channel_id(app: &App, channel: ChannelParam) -> Result<String, Error> {
+    match channel {
+        ChannelParam::ChannelId(id) => Ok(id),
+        ChannelParam::Channel(name) => app.get_user_id_by_name(&name).await,
+    }
+}
+
+pub async fn reload_config(
+    Extension(bot_tx): Extension<Sender<BotMessage>>,
+    app: State<App>,
+) -> Result<(), Error> {
+    app.reload_config(&bot_tx).await?;
+
+    Ok(())
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct PurgeRequest {
+    #[serde(flatten)]
+    pub channel: ChannelParam,
+    /// First day to delete (YYYY-MM-DD). If neither `from` nor `to` is set, all logs are deleted and the channel is left.
+    #[schemars(with = "Option<String>")]
+    pub from: Option<NaiveDate>,
+    /// Last day to delete (YYYY-MM-DD), inclusive
+    #[schemars(with = "Option<String>")]
+    pub to: Option<NaiveDate>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct PurgeStatusParams {
+    #[serde(flatten)]
+    pub channel: ChannelParam,
+}
+
+#[derive(Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PurgeStatus {
+    /// Deletions started by this instance which are still in progress
+    pub mutations: Vec<MutationProgress>,
+    /// Whether all logs are deleted again once the current deletions have finished,
+    /// to remove messages which were still being written when the channel was left
+    pub repeating: bool,
+}
+
+pub async fn purge_channel(
+    Extension(bot_tx): Extension<Sender<BotMessage>>,
+    app: State<App>,
+    Json(PurgeRequest { channel, from, to }): Json<PurgeRequest>,
+) -> Result<Json<PurgeStatus>, Error> {
+    if from.zip(to).map_or(false, |(from, to)| from > to) {
+        return Err(Error::InvalidParam(
+            "`to` cannot be before `from`".to_owned(),
+        ));
+    }
+
+    let channel_id = resolve_channel_id(&app, channel).await?;
+
+    if from.is_none() && to.is_none() {
+        let users = app.get_users(vec![channel_id.clone()], vec![]).await?;
+        let names: Vec<String> = users.into_values().collect();
+
+        if !names.is_empty() {
+            bot_tx.send(BotMessage::PartChannels(names)).await.unwrap();
+        }
+    }
+
+    app.purge_channel_logs(&channel_id, from, to).await?;
+
+    let (mutations, repeating) = app.read_purge_status(&channel_id).await?;
+    Ok(Json(PurgeStatus {
+        mutations,
+        repeating,
+    }))
+}
+
+pub async fn get_purge_status(
+    app: State<App>,
+    Query(PurgeStatusParams { channel }): Query<PurgeStatusParams>,
+) -> Result<Json<PurgeStatus>, Error> {
+    let channel_id = resolve_channel_id(&app, channel).await?;
+
+    let (mutations, repeating) = app.read_purge_status(&channel_id).await?;
+    Ok(Json(PurgeStatus {
+        mutations,
+        repeating,
+    }))
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct UserExportParams {
+    #[serde(flatten)]
+    pub user: UserParam,
+}
+
+pub async fn export_user_logs(
+    app: State<App>,
+    Query(UserExportParams { user }): Query<UserExportParams>,
+) -> Result<impl IntoApiResponse, Error> {
+    let user_id = resolve_user_id(&app, user).await?;
+    let export = UserExportResponse::new(&app.db, user_id).await?;
+
+    Ok((no_cache_header(), export))
+}
@@ -1,36 +1,51 @@
 use super::{
-    responders::logs::LogsResponse,
+    responders::{
+        live::{LiveEvent, LiveResponse},
+        logs::LogsResponse,
+    },
     schema::{
         AvailableLogs, AvailableLogsParams, Channel, ChannelIdType, ChannelLogsPath, ChannelParam,
-        ChannelsList, LogsParams, LogsPathChannel, UserLogPathParams, UserLogsPath, UserParam,
+        ChannelStats, ChannelStatsParams, ChannelsList, LiveParams, LogsParams, LogsPathChannel,
+        LogsRangeParams, NameHistoryPath, SearchParams, ThreadPath, TopChatter, TopChatters,
+        UserLogPathParams, UserLogsPath, UserParam, UserStats, UserStatsParams,
     },
 };
 use crate::{
     app::App,
     db::{
         read_available_channel_logs, read_available_user_logs, read_channel,
-        read_random_channel_line, read_random_user_line, read_user,
+        read_channel_daily_stats, read_channel_range, read_channel_top_chatters, read_name_history,
+        read_random_channel_line, read_random_user_line, read_reply_thread, read_user,
+        read_user_channel_stats, read_user_monthly_stats, read_user_range, schema::Message,
+        search_channel,
     },
     error::Error,
     logs::{
         schema::{ChannelLogDate, UserLogDate},
         stream::LogsStream,
     },
-    Result,
+    Result, ShutdownRx,
 };
 use aide::axum::IntoApiResponse;
 use axum::{
     extract::{Path, Query, RawQuery, State},
     headers::CacheControl,
     response::Redirect,
-    Json, TypedHeader,
+    Extension, Json, TypedHeader,
 };
+use chrono::{DateTime, Utc};
+use futures::{stream, StreamExt};
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
-use std::time::Duration;
-use tracing::debug;
+use std::{cmp::min, time::Duration};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{debug, warn};
+
+const DEFAULT_TOP_CHATTERS_LIMIT: u64 = 10;
+const MAX_TOP_CHATTERS_LIMIT: u64 = 100;
 
 pub async fn get_channels(app: State<App>) -> impl IntoApiResponse {
-    let channel_ids = app.config.channels.read().unwrap().clone();
+    let config = app.config.load_full();
+    let channel_ids = config.channels.read().unwrap().clone();
 
     let channels = app
         .get_users(Vec::from_iter(channel_ids), vec![])
@@ -40,7 +55,11 @@ pub async fn get_channels(app: State<App>) -> impl IntoApiResponse {
     let json = Json(ChannelsList {
         channels: channels
             .into_iter()
-            .map(|(user_id, name)| Channel { name, user_id })
+            .map(|(user_id, name)| Channel {
+                retention_days: config.channel_retention_days.get(&user_id).copied(),
+                name,
+                user_id,
+            })
             .collect(),
     });
     (cache_header(600), json)
@@ -169,6 +188,248 @@ async fn get_user_logs(
     Ok((cache, logs))
 }
 
+pub async fn get_channel_logs_range(
+    app: State<App>,
+    Path(LogsPathChannel {
+        channel_id_type,
+        channel,
+    }): Path<LogsPathChannel>,
+    Query(range_params): Query<LogsRangeParams>,
+    Query(logs_params): Query<LogsParams>,
+) -> Result<impl IntoApiResponse> {
+    let (from, to) = range_params.range()?;
+
+    let channel_id = match channel_id_type {
+        ChannelIdType::Name => app.get_user_id_by_name(&channel).await?,
+        ChannelIdType::Id => channel,
+    };
+
+    app.check_opted_out(&channel_id, None)?;
+
+    let stream = read_channel_range(
+        &app.db,
+        &channel_id,
+        from,
+        to,
+        logs_params.reverse,
+        logs_params.limit,
+        logs_params.offset,
+    )
+    .await?;
+
+    let logs = LogsResponse {
+        stream,
+        response_type: logs_params.response_type(),
+    };
+    Ok((range_cache_header(to), logs))
+}
+
+pub async fn get_user_logs_range_by_name(
+    app: State<App>,
+    Path(UserLogPathParams {
+        channel_id_type,
+        channel,
+        user,
+    }): Path<UserLogPathParams>,
+    range_params: Query<LogsRangeParams>,
+    logs_params: Query<LogsParams>,
+) -> Result<impl IntoApiResponse> {
+    let user_id = app.get_user_id_by_name(&user).await?;
+    get_user_logs_range(
+        app,
+        channel_id_type,
+        channel,
+        user_id,
+        range_params,
+        logs_params,
+    )
+    .await
+}
+
+pub async fn get_user_logs_range_by_id(
+    app: State<App>,
+    Path(UserLogPathParams {
+        channel_id_type,
+        channel,
+        user,
+    }): Path<UserLogPathParams>,
+    range_params: Query<LogsRangeParams>,
+    logs_params: Query<LogsParams>,
+) -> Result<impl IntoApiResponse> {
+    get_user_logs_range(
+        app,
+        channel_id_type,
+        channel,
+        user,
+        range_params,
+        logs_params,
+    )
+    .await
+}
+
+async fn get_user_logs_range(
+    app: State<App>,
+    channel_id_type: ChannelIdType,
+    channel: String,
+    user_id: String,
+    Query(range_params): Query<LogsRangeParams>,
+    Query(logs_params): Query<LogsParams>,
+) -> Result<impl IntoApiResponse> {
+    let (from, to) = range_params.range()?;
+
+    let channel_id = match channel_id_type {
+        ChannelIdType::Name => app.get_user_id_by_name(&channel).await?,
+        ChannelIdType::Id => channel,
+    };
+
+    app.check_opted_out(&channel_id, Some(&user_id))?;
+
+    let stream = read_user_range(
+        &app.db,
+        &channel_id,
+        &user_id,
+        from,
+        to,
+        logs_params.reverse,
+        logs_params.limit,
+        logs_params.offset,
+    )
+    .await?;
+
+    let logs = LogsResponse {
+        stream,
+        response_type: logs_params.response_type(),
+    };
+    Ok((range_cache_header(to), logs))
+}
+
+pub async fn search_channel_logs(
+    app: State<App>,
+    Path(LogsPathChannel {
+        channel_id_type,
+        channel,
+    }): Path<LogsPathChannel>,
+    Query(SearchParams { q, user, from, to }): Query<SearchParams>,
+    Query(logs_params): Query<LogsParams>,
+) -> Result<impl IntoApiResponse> {
+    if q.trim().is_empty() {
+        return Err(Error::InvalidParam(
+            "Search query cannot be empty".to_owned(),
+        ));
+    }
+
+    let channel_id = match channel_id_type {
+        ChannelIdType::Name => app.get_user_id_by_name(&channel).await?,
+        ChannelIdType::Id => channel,
+    };
+
+    let user_id = match user {
+        Some(UserParam::UserId(id)) => Some(id),
+        Some(UserParam::User(name)) => Some(app.get_user_id_by_name(&name).await?),
+        None => None,
+    };
+
+    app.check_opted_out(&channel_id, user_id.as_deref())?;
+
+    let stream = search_channel(
+        &app.db,
+        &channel_id,
+        &q,
+        user_id.as_deref(),
+        from,
+        to,
+        logs_params.reverse,
+        logs_params.limit,
+        logs_params.offset,
+    )
+    .await?;
+
+    let logs = LogsResponse {
+        stream,
+        response_type: logs_params.response_type(),
+    };
+    Ok((no_cache_header(), logs))
+}
+
+pub async fn live_channel_logs(
+    app: State<App>,
+    Extension(live_tx): Extension<broadcast::Sender<Message<'static>>>,
+    Extension(shutdown_rx): Extension<ShutdownRx>,
+    Path(LogsPathChannel {
+        channel_id_type,
+        channel,
+    }): Path<LogsPathChannel>,
+    Query(LiveParams { user }): Query<LiveParams>,
+    Query(logs_params): Query<LogsParams>,
+) -> Result<impl IntoApiResponse> {
+    let channel_id = match channel_id_type {
+        ChannelIdType::Name => app.get_user_id_by_name(&channel).await?,
+        ChannelIdType::Id => channel,
+    };
+
+    let user_id = match user {
+        Some(UserParam::UserId(id)) => Some(id),
+        Some(UserParam::User(name)) => Some(app.get_user_id_by_name(&name).await?),
+        None => None,
+    };
+
+    app.check_opted_out(&channel_id, user_id.as_deref())?;
+
+    let app = app.0;
+    let receiver = live_tx.subscribe();
+
+    let stream = stream::unfold(
+        (receiver, shutdown_rx),
+        move |(mut receiver, mut shutdown_rx)| {
+            let app = app.clone();
+            let channel_id = channel_id.clone();
+            let user_id = user_id.clone();
+
+            async move {
+                loop {
+                    let result = tokio::select! {
+                        result = receiver.recv() => result,
+                        _ = shutdown_rx.changed() => return None,
+                    };
+
+                    match result {
+                        Ok(message) => {
+                            let matches_user = match &user_id {
+                                Some(user_id) => message.user_id == *user_id,
+                                None => true,
+                            };
+
+                            // Users may opt out while the stream is open
+                            if message.channel_id == channel_id
+                                && matches_user
+                                && app
+                                    .check_opted_out(&message.channel_id, Some(&message.user_id))
+                                    .is_ok()
+                            {
+                                return Some((
+                                    LiveEvent::Message(message),
+                                    (receiver, shutdown_rx),
+                                ));
+                            }
+                        }
+                        Err(RecvError::Lagged(count)) => {
+                            warn!("Live logs subscriber skipped {count} messages");
+                            return Some((LiveEvent::Lagged(count), (receiver, shutdown_rx)));
+                        }
+                        Err(RecvError::Closed) => return None,
+                    }
+                }
+            }
+        },
+    );
+
+    let live = LiveResponse {
+        stream: stream.boxed(),
+        response_type: logs_params.response_type(),
+    };
+    Ok((no_cache_header(), live))
+}
+
 pub async fn list_available_logs(
     Query(AvailableLogsParams { user, channel }): Query<AvailableLogsParams>,
     app: State<App>,
@@ -197,6 +458,134 @@ pub async fn list_available_logs(
     }
 }
 
+pub async fn get_name_history(
+    app: State<App>,
+    Path(NameHistoryPath { user_id }): Path<NameHistoryPath>,
+) -> Result<impl IntoApiResponse> {
+    if app.config.load().opt_out.contains_key(&user_id) {
+        return Err(Error::OptedOut);
+    }
+
+    let names = read_name_history(&app.db, &user_id).await?;
+
+    if !names.is_empty() {
+        Ok((cache_header(600), Json(names)))
+    } else {
+        Err(Error::NotFound)
+    }
+}
+
+pub async fn get_user_stats(
+    Query(UserStatsParams { user }): Query<UserStatsParams>,
+    app: State<App>,
+) -> Result<impl IntoApiResponse> {
+    let user_id = match user {
+        UserParam::UserId(id) => id,
+        UserParam::User(name) => app.get_user_id_by_name(&name).await?,
+    };
+
+    if app.config.load().opt_out.contains_key(&user_id) {
+        return Err(Error::OptedOut);
+    }
+
+    let mut excluded_channel_ids: Vec<String> = app
+        .config
+        .load()
+        .opt_out
+        .iter()
+        .map(|entry| entry.key().clone())
+        .collect();
+    if let Some(channel_ids) = app.config.load().channel_opt_out.get(&user_id) {
+        excluded_channel_ids.extend(channel_ids.iter().cloned());
+    }
+    let excluded_channel_ids: Vec<&str> = excluded_channel_ids.iter().map(String::as_str).collect();
+
+    let channels = read_user_channel_stats(&app.db, &user_id, &excluded_channel_ids).await?;
+    let months = read_user_monthly_stats(&app.db, &user_id, &excluded_channel_ids).await?;
+
+    let stats = UserStats::new(user_id, channels, months).ok_or(Error::NotFound)?;
+    Ok((cache_header(600), Json(stats)))
+}
+
+pub async fn get_channel_stats(
+    app: State<App>,
+    Path(LogsPathChannel {
+        channel_id_type,
+        channel,
+    }): Path<LogsPathChannel>,
+    Query(params): Query<ChannelStatsParams>,
+) -> Result<impl IntoApiResponse> {
+    let (from, to) = params.period()?;
+
+    let channel_id = match channel_id_type {
+        ChannelIdType::Name => app.get_user_id_by_name(&channel).await?,
+        ChannelIdType::Id => channel,
+    };
+
+    app.check_opted_out(&channel_id, None)?;
+
+    let days =
+        read_channel_daily_stats(&app.db, &channel_id, from.date_naive(), to.date_naive()).await?;
+
+    Ok((range_cache_header(to), Json(ChannelStats { days })))
+}
+
+pub async fn get_channel_top_chatters(
+    app: State<App>,
+    Path(LogsPathChannel {
+        channel_id_type,
+        channel,
+    }): Path<LogsPathChannel>,
+    Query(params): Query<ChannelStatsParams>,
+) -> Result<impl IntoApiResponse> {
+    let (from, to) = params.period()?;
+    let limit = min(
+        params.limit.unwrap_or(DEFAULT_TOP_CHATTERS_LIMIT),
+        MAX_TOP_CHATTERS_LIMIT,
+    );
+
+    let channel_id = match channel_id_type {
+        ChannelIdType::Name => app.get_user_id_by_name(&channel).await?,
+        ChannelIdType::Id => channel,
+    };
+
+    app.check_opted_out(&channel_id, None)?;
+
+    let excluded_user_ids = app.config.load().opted_out_user_ids(&channel_id);
+    let excluded_user_ids: Vec<&str> = excluded_user_ids.iter().map(String::as_str).collect();
+
+    let top_chatters = read_channel_top_chatters(
+        &app.db,
+        &channel_id,
+        from.date_naive(),
+        to.date_naive(),
+        &excluded_user_ids,
+        limit,
+    )
+    .await?;
+
+    let names = app
+        .get_users(
+            top_chatters
+                .iter()
+                .map(|(user_id, _)| user_id.clone())
+                .collect(),
+            vec![],
+        )
+        .await?;
+
+    let chatters = top_chatters
+        .into_iter()
+        .map(|(user_id, messages)| TopChatter {
+            name: names.get(&user_id).cloned(),
+            user_id,
+            messages,
+        })
+        .collect();
+
+    Ok((range_cache_header(to), Json(TopChatters { chatters })))
+}
+
 pub async fn redirect_to_latest_channel_logs(
     Path(LogsPathChannel {
         channel_id_type,
@@ -273,6 +662,39 @@ async fn redirect_to_latest_user_logs(
     Ok(Redirect::to(&new_uri))
 }
 
+pub async fn get_reply_thread(
+    app: State<App>,
+    Path(ThreadPath {
+        channel_id_type,
+        channel,
+        message_id,
+    }): Path<ThreadPath>,
+    Query(logs_params): Query<LogsParams>,
+) -> Result<impl IntoApiResponse> {
+    let channel_id = match channel_id_type {
+        ChannelIdType::Name => app.get_user_id_by_name(&channel).await?,
+        ChannelIdType::Id => channel,
+    };
+
+    app.check_opted_out(&channel_id, None)?;
+
+    let stream = read_reply_thread(
+        &app.db,
+        &channel_id,
+        &message_id,
+        logs_params.reverse,
+        logs_params.limit,
+        logs_params.offset,
+    )
+    .await?;
+
+    let logs = LogsResponse {
+        stream,
+        response_type: logs_params.response_type(),
+    };
+    Ok((no_cache_header(), logs))
+}
+
 pub async fn random_channel_line(
     app: State<App>,
     Path(LogsPathChannel {
@@ -373,6 +795,15 @@ fn cache_header(secs: u64) -> TypedHeader<CacheControl> {
     )
 }
 
+/// Ranges which end before the current day are not going to change
+fn range_cache_header(to: DateTime<Utc>) -> TypedHeader<CacheControl> {
+    if to.date_naive() < Utc::now().date_naive() {
+        cache_header(36000)
+    } else {
+        no_cache_header()
+    }
+}
+
 pub fn no_cache_header() -> TypedHeader<CacheControl> {
     TypedHeader(CacheControl::new().with_no_cache())
 }
@@ -1,12 +1,15 @@
 mod admin;
 mod frontend;
 mod handlers;
-mod responders;
+pub mod responders;
 pub mod schema;
 mod trace_layer;
 
 use self::handlers::no_cache_header;
-use crate::{app::App, bot::BotMessage, web::admin::admin_auth, ShutdownRx};
+use crate::{
+    app::App, bot::BotMessage, config::AdminScope, db::schema::Message, web::admin::admin_auth,
+    ShutdownRx,
+};
 use aide::{
     axum::{
         routing::{get, get_with, post, post_with},
@@ -23,14 +26,19 @@ use std::{
     str::FromStr,
     sync::Arc,
 };
-use tokio::sync::mpsc::Sender;
+use tokio::sync::{broadcast, mpsc::Sender};
 use tower_http::{
     compression::CompressionLayer, cors::CorsLayer, normalize_path::NormalizePath,
     trace::TraceLayer, CompressionLevel,
 };
 use tracing::{debug, info};
 
-pub async fn run(app: App, mut shutdown_rx: ShutdownRx, bot_tx: Sender<BotMessage>) {
+pub async fn run(
+    app: App,
+    mut shutdown_rx: ShutdownRx,
+    bot_tx: Sender<BotMessage>,
+    live_tx: broadcast::Sender<Message<'static>>,
+) {
     aide::gen::on_error(|error| {
         panic!("Could not generate docs: {error}");
     });
@@ -40,25 +48,100 @@ pub async fn run(app: App, mut shutdown_rx: ShutdownRx, bot_tx: Sender<BotMessag
     metrics_prometheus::install();
 
     let listen_address =
-        parse_listen_addr(&app.config.listen_address).expect("Invalid listen address");
+        parse_listen_addr(&app.config.load().listen_address).expect("Invalid listen address");
 
     let cors = CorsLayer::permissive();
 
     let mut api = OpenApi::default();
 
-    let admin_routes = ApiRouter::new()
+    let channel_admin_routes = ApiRouter::new()
         .api_route(
             "/channels",
             post_with(admin::add_channels, |mut op| {
-                admin::admin_auth_doc(&mut op);
+                admin::admin_auth_doc(&mut op, AdminScope::Channels);
                 op.tag("Admin").description("Join the specified channels")
             })
             .delete_with(admin::remove_channels, |mut op| {
-                admin::admin_auth_doc(&mut op);
+                admin::admin_auth_doc(&mut op, AdminScope::Channels);
                 op.tag("Admin").description("Leave the specified channels")
             }),
         )
-        .route_layer(middleware::from_fn_with_state(app.clone(), admin_auth))
+        .api_route(
+            "/config/reload",
+            post_with(admin::reload_config, |mut op| {
+                admin::admin_auth_doc(&mut op, AdminScope::Channels);
+                op.tag("Admin")
+                    .description("Reload the config file and join or part changed channels")
+            }),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            (app.clone(), AdminScope::Channels),
+            admin_auth,
+        ));
+
+    let optout_admin_routes = ApiRouter::new()
+        .api_route(
+            "/optout",
+            get_with(admin::list_optouts, |mut op| {
+                admin::admin_auth_doc(&mut op, AdminScope::Optout);
+                op.tag("Admin").description("List opted out users")
+            })
+            .post_with(admin::add_optout, |mut op| {
+                admin::admin_auth_doc(&mut op, AdminScope::Optout);
+                op.tag("Admin").description(
+                    "Opt out a user and delete their logs, optionally only in one channel",
+                )
+            })
+            .delete_with(admin::remove_optout, |mut op| {
+                admin::admin_auth_doc(&mut op, AdminScope::Optout);
+                op.tag("Admin")
+                    .description("Remove an opt out, deleted logs are not restored")
+            }),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            (app.clone(), AdminScope::Optout),
+            admin_auth,
+        ));
+
+    let purge_admin_routes = ApiRouter::new()
+        .api_route(
+            "/purge",
+            get_with(admin::get_purge_status, |mut op| {
+                admin::admin_auth_doc(&mut op, AdminScope::Purge);
+                op.tag("Admin")
+                    .description("Get the progress of deleting the channel's logs")
+            })
+            .post_with(admin::purge_channel, |mut op| {
+                admin::admin_auth_doc(&mut op, AdminScope::Purge);
+                op.tag("Admin").description(
+                    "Delete the channel's logs, leaving the channel if no date range is given",
+                )
+            }),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            (app.clone(), AdminScope::Purge),
+            admin_auth,
+        ));
+
+    let read_private_admin_routes = ApiRouter::new()
+        .api_route(
+            "/users/export",
+            get_with(admin::export_user_logs, |mut op| {
+                admin::admin_auth_doc(&mut op, AdminScope::ReadPrivate);
+                op.tag("Admin")
+                    .description("Export all logs of a user across all channels, along with their name history and statistics")
+            }),
+        )
+        .route_layer(middleware::from_fn_with_state(
+            (app.clone(), AdminScope::ReadPrivate),
+            admin_auth,
+        ));
+
+    let admin_routes = ApiRouter::new()
+        .merge(channel_admin_routes)
+        .merge(optout_admin_routes)
+        .merge(purge_admin_routes)
+        .merge(read_private_admin_routes)
         .layer(Extension(bot_tx));
 
     let app = ApiRouter::new()
@@ -75,6 +158,18 @@ pub async fn run(app: App, mut shutdown_rx: ShutdownRx, bot_tx: Sender<BotMessag
                 op.description("List available logs")
             }),
         )
+        .api_route(
+            "/stats/user",
+            get_with(handlers::get_user_stats, |op| {
+                op.description("Get message statistics of a user across all channels")
+            }),
+        )
+        .api_route(
+            "/namehistory/:user_id",
+            get_with(handlers::get_name_history, |op| {
+                op.description("List all logins and display names used by the user")
+            }),
+        )
         .api_route(
             "/:channel_id_type/:channel",
             get_with(handlers::redirect_to_latest_channel_logs, |op| {
@@ -118,6 +213,54 @@ pub async fn run(app: App, mut shutdown_rx: ShutdownRx, bot_tx: Sender<BotMessag
                 op.description("Get a random line from the channel's logs")
             }),
         )
+        .api_route(
+            "/:channel_id_type/:channel/range",
+            get_with(handlers::get_channel_logs_range, |op| {
+                op.description("Get channel logs from the given time range")
+            }),
+        )
+        .api_route(
+            "/:channel_id_type/:channel/user/:user/range",
+            get_with(handlers::get_user_logs_range_by_name, |op| {
+                op.description("Get user logs in a channel from the given time range")
+            }),
+        )
+        .api_route(
+            "/:channel_id_type/:channel/userid/:user/range",
+            get_with(handlers::get_user_logs_range_by_id, |op| {
+                op.description("Get user logs in a channel from the given time range")
+            }),
+        )
+        .api_route(
+            "/:channel_id_type/:channel/stats",
+            get_with(handlers::get_channel_stats, |op| {
+                op.description("Get daily message and chatter counts of the channel")
+            }),
+        )
+        .api_route(
+            "/:channel_id_type/:channel/stats/top",
+            get_with(handlers::get_channel_top_chatters, |op| {
+                op.description("Get the most active chatters of the channel")
+            }),
+        )
+        .api_route(
+            "/:channel_id_type/:channel/search",
+            get_with(handlers::search_channel_logs, |op| {
+                op.description("Search the channel's logs for messages containing the given text")
+            }),
+        )
+        .api_route(
+            "/:channel_id_type/:channel/thread/:message_id",
+            get_with(handlers::get_reply_thread, |op| {
+                op.description("Get the reply thread containing the given message")
+            }),
+        )
+        .api_route(
+            "/:channel_id_type/:channel/live",
+            get_with(handlers::live_channel_logs, |op| {
+                op.description("Stream new messages in the channel as server-sent events")
+            }),
+        )
         .api_route(
             "/:channel_id_type/:channel/userid/:user/random",
             get_with(handlers::random_user_line_by_id, |op| {
@@ -148,6 +291,8 @@ pub async fn run(app: App, mut shutdown_rx: ShutdownRx, bot_tx: Sender<BotMessag
         .route("/metrics", get(metrics))
         .finish_api(&mut api)
         .layer(Extension(Arc::new(api)))
+        .layer(Extension(live_tx))
+        .layer(Extension(shutdown_rx.clone()))
         .with_state(app)
         .layer(cors)
         .layer(CompressionLayer::new().quality(CompressionLevel::Fastest));
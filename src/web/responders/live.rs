@@ -0,0 +1,106 @@
+use super::logs::{JsonResponseType, LogsResponseType};
+use crate::{
+    db::schema::Message,
+    logs::schema::message::{BasicMessage, FullMessage, ResponseMessage},
+};
+use aide::{openapi::MediaType, OperationOutput};
+use axum::response::{
+    sse::{Event, KeepAlive},
+    IntoResponse, Response, Sse,
+};
+use futures::{stream::BoxStream, StreamExt};
+use std::convert::Infallible;
+use tracing::warn;
+
+pub struct LiveResponse {
+    pub stream: BoxStream<'static, LiveEvent>,
+    pub response_type: LogsResponseType,
+}
+
+pub enum LiveEvent {
+    Message(Message<'static>),
+    /// The subscriber could not keep up and this many messages were skipped
+    Lagged(u64),
+}
+
+impl IntoResponse for LiveResponse {
+    fn into_response(self) -> Response {
+        let response_type = self.response_type;
+        let stream = self.stream.filter_map(move |live_event| {
+            let event = match live_event {
+                LiveEvent::Message(message) => render_event(&message.raw, &response_type),
+                LiveEvent::Lagged(count) => {
+                    Some(Event::default().event("lagged").data(count.to_string()))
+                }
+            };
+            async move { event.map(Ok::<_, Infallible>) }
+        });
+
+        Sse::new(stream)
+            .keep_alive(KeepAlive::default())
+            .into_response()
+    }
+}
+
+fn render_event(raw: &str, response_type: &LogsResponseType) -> Option<Event> {
+    if matches!(response_type, LogsResponseType::Raw) {
+        return Some(Event::default().data(raw));
+    }
+
+    let irc_message = match twitch::Message::parse(raw.to_owned()) {
+        Ok(msg) => msg,
+        Err(err) => {
+            warn!("Could not parse message `{err}`");
+            return None;
+        }
+    };
+
+    let result = match response_type {
+        LogsResponseType::Text => {
+            FullMessage::from_irc_message(&irc_message).map(|message| message.to_string())
+        }
+        LogsResponseType::Json(JsonResponseType::Full) => {
+            FullMessage::from_irc_message(&irc_message).map(serialize_message)
+        }
+        _ => BasicMessage::from_irc_message(&irc_message).map(serialize_message),
+    };
+
+    match result {
+        Ok(data) => Some(Event::default().data(data)),
+        Err(err) => {
+            warn!("Could not parse message: {err}, irc: {:?}", irc_message);
+            None
+        }
+    }
+}
+
+fn serialize_message<'a, T: ResponseMessage<'a>>(mut message: T) -> String {
+    message.unescape_tags();
+    serde_json::to_string(&message).unwrap()
+}
+
+impl OperationOutput for LiveResponse {
+    type Inner = Self;
+
+    fn operation_response(
+        _: &mut aide::gen::GenContext,
+        _: &mut aide::openapi::Operation,
+    ) -> Option<aide::openapi::Response> {
+        Some(aide::openapi::Response {
+            description: "Server-sent events stream of new messages. If the server could not keep up with the messages, a `lagged` event is sent, containing the amount of skipped messages across all channels.".into(),
+            content: [("text/event-stream".into(), MediaType::default())]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        })
+    }
+
+    fn inferred_responses(
+        ctx: &mut aide::gen::GenContext,
+        operation: &mut aide::openapi::Operation,
+    ) -> Vec<(Option<u16>, aide::openapi::Response)> {
+        let res = Self::operation_response(ctx, operation).unwrap();
+
+        vec![(Some(200), res)]
+    }
+}
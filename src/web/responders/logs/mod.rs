@@ -4,10 +4,9 @@ mod ndjson_stream;
 mod text_stream;
 
 pub use json_stream::JsonResponseType;
+pub use ndjson_stream::NdJsonLogsStream;
 
-use self::{
-    json_stream::JsonLogsStream, ndjson_stream::NdJsonLogsStream, text_stream::TextLogsStream,
-};
+use self::{json_stream::JsonLogsStream, text_stream::TextLogsStream};
 use crate::logs::{schema::message::FullMessage, stream::LogsStream};
 use aide::OperationOutput;
 use axum::{
@@ -1 +1,3 @@
+pub mod live;
 pub mod logs;
+pub mod user_export;
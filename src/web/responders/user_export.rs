@@ -0,0 +1,130 @@
+use super::logs::NdJsonLogsStream;
+use crate::{
+    db::{read_all_user_logs, read_name_history, read_user_channel_stats, read_user_monthly_stats},
+    web::schema::{PreviousName, UserStats},
+    Result,
+};
+use aide::{openapi::MediaType, OperationOutput};
+use axum::{
+    body::StreamBody,
+    http::HeaderValue,
+    response::{IntoResponse, Response},
+};
+use futures::{
+    stream::{self, BoxStream},
+    StreamExt, TryStreamExt,
+};
+use reqwest::header::CONTENT_TYPE;
+use serde::Serialize;
+
+const MESSAGE_RECORD_PREFIX: &[u8] = br#"{"type":"message","message":"#;
+
+/// Everything stored about a user as NDJSON. The first line is the user record,
+/// which is followed by one message record per line.
+pub struct UserExportResponse {
+    pub stream: BoxStream<'static, Result<Vec<u8>>>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename = "user", rename_all = "camelCase")]
+struct UserRecord {
+    #[serde(rename = "userID")]
+    user_id: String,
+    names: Vec<PreviousName>,
+    stats: Option<UserStats>,
+}
+
+impl UserExportResponse {
+    pub async fn new(db: &clickhouse::Client, user_id: String) -> Result<Self> {
+        let logs = read_all_user_logs(db, &user_id).await?;
+
+        let names = read_name_history(db, &user_id).await?;
+        let channels = read_user_channel_stats(db, &user_id, &[]).await?;
+        let months = read_user_monthly_stats(db, &user_id, &[]).await?;
+        let user_record = UserRecord {
+            stats: UserStats::new(user_id.clone(), channels, months),
+            user_id,
+            names,
+        };
+
+        let mut user_line = serde_json::to_vec(&user_record).unwrap();
+        user_line.push(b'\n');
+
+        let messages = NdJsonLogsStream::new(logs).map_ok(|chunk| message_records(&chunk));
+        let stream = stream::once(async { Ok(user_line) })
+            .chain(messages)
+            .boxed();
+
+        Ok(Self { stream })
+    }
+}
+
+/// Wraps every serialized message of the chunk into a message record
+fn message_records(chunk: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(chunk.len() * 2);
+
+    for line in chunk.split(|byte| *byte == b'\n') {
+        if !line.is_empty() {
+            buf.extend_from_slice(MESSAGE_RECORD_PREFIX);
+            buf.extend_from_slice(line);
+            buf.extend_from_slice(b"}\n");
+        }
+    }
+
+    buf
+}
+
+impl IntoResponse for UserExportResponse {
+    fn into_response(self) -> Response {
+        (
+            [(
+                CONTENT_TYPE,
+                HeaderValue::from_static("application/x-ndjson"),
+            )],
+            StreamBody::new(self.stream),
+        )
+            .into_response()
+    }
+}
+
+impl OperationOutput for UserExportResponse {
+    type Inner = Self;
+
+    fn operation_response(
+        _: &mut aide::gen::GenContext,
+        _: &mut aide::openapi::Operation,
+    ) -> Option<aide::openapi::Response> {
+        Some(aide::openapi::Response {
+            description: "NDJSON export of the user. The first line is a `user` record with the name history and statistics of the user, each following line is a `message` record.".into(),
+            content: [("application/x-ndjson".into(), MediaType::default())]
+                .into_iter()
+                .collect(),
+            ..Default::default()
+        })
+    }
+
+    fn inferred_responses(
+        ctx: &mut aide::gen::GenContext,
+        operation: &mut aide::openapi::Operation,
+    ) -> Vec<(Option<u16>, aide::openapi::Response)> {
+        let res = Self::operation_response(ctx, operation).unwrap();
+
+        vec![(Some(200), res)]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::message_records;
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn wrap_messages_into_records() {
+        let chunk = b"{\"text\":\"a\"}\n{\"text\":\"b\\nc\"}\n";
+
+        assert_eq!(
+            String::from_utf8(message_records(chunk)).unwrap(),
+            "{\"type\":\"message\",\"message\":{\"text\":\"a\"}}\n{\"type\":\"message\",\"message\":{\"text\":\"b\\nc\"}}\n"
+        );
+    }
+}
@@ -1,8 +1,16 @@
 use super::responders::logs::{JsonResponseType, LogsResponseType};
-use crate::logs::schema::{ChannelLogDate, UserLogDate};
+use crate::{
+    error::Error,
+    logs::schema::{ChannelLogDate, UserLogDate},
+};
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use clickhouse::Row;
 use schemars::JsonSchema;
 use serde::{Deserialize, Deserializer, Serialize};
-use std::{fmt::Display, num::ParseIntError};
+use std::{cmp::min, fmt::Display, num::ParseIntError};
+
+const DEFAULT_STATS_PERIOD_DAYS: i64 = 30;
+const MAX_RANGE_DAYS: i64 = 31;
 
 #[derive(Serialize, JsonSchema)]
 pub struct ChannelsList {
@@ -14,6 +22,9 @@ pub struct Channel {
     pub name: String,
     #[serde(rename = "userID")]
     pub user_id: String,
+    /// Days after which logs are deleted, not set if they are kept forever
+    #[serde(rename = "retentionDays", skip_serializing_if = "Option::is_none")]
+    pub retention_days: Option<u32>,
 }
 
 #[derive(Debug, Deserialize, JsonSchema)]
@@ -76,6 +87,14 @@ pub struct LogsPathChannel {
     pub channel: String,
 }
 
+#[derive(Deserialize, JsonSchema)]
+pub struct ThreadPath {
+    pub channel_id_type: ChannelIdType,
+    pub channel: String,
+    /// Id of any message in the thread
+    pub message_id: String,
+}
+
 #[derive(Deserialize, Debug, JsonSchema)]
 #[serde(rename_all = "camelCase")]
 pub struct LogsParams {
@@ -181,3 +200,375 @@ pub struct UserLogPathParams {
     pub channel: String,
     pub user: String,
 }
+
+#[derive(Deserialize, JsonSchema)]
+pub struct LiveParams {
+    #[serde(flatten)]
+    pub user: Option<UserParam>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct SearchParams {
+    /// Text to search for (case insensitive)
+    pub q: String,
+    #[serde(flatten)]
+    pub user: Option<UserParam>,
+    /// Only include messages sent at or after this time (RFC3339 or unix milliseconds)
+    #[serde(default, deserialize_with = "deserialize_optional_timestamp_param")]
+    #[schemars(with = "Option<String>")]
+    pub from: Option<DateTime<Utc>>,
+    /// Only include messages sent before this time (RFC3339 or unix milliseconds)
+    #[serde(default, deserialize_with = "deserialize_optional_timestamp_param")]
+    #[schemars(with = "Option<String>")]
+    pub to: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct LogsRangeParams {
+    /// Start of the range, inclusive (RFC3339 or unix milliseconds)
+    #[serde(deserialize_with = "deserialize_timestamp_param")]
+    #[schemars(with = "String")]
+    pub from: DateTime<Utc>,
+    /// End of the range, exclusive (RFC3339 or unix milliseconds). Defaults to the current time.
+    /// Ranges are at most 31 days long, later ends are moved to 31 days after `from`
+    #[serde(default, deserialize_with = "deserialize_optional_timestamp_param")]
+    #[schemars(with = "Option<String>")]
+    pub to: Option<DateTime<Utc>>,
+}
+
+impl LogsRangeParams {
+    pub fn range(&self) -> Result<(DateTime<Utc>, DateTime<Utc>), Error> {
+        let to = self.to.unwrap_or_else(Utc::now);
+
+        if self.from >= to {
+            return Err(Error::InvalidParam(
+                "`from` must be earlier than `to`".to_owned(),
+            ));
+        }
+
+        let to = min(to, self.from + Duration::days(MAX_RANGE_DAYS));
+
+        Ok((self.from, to))
+    }
+}
+
+fn parse_timestamp_param(value: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(millis) = value.parse::<i64>() {
+        Utc.timestamp_millis_opt(millis)
+            .single()
+            .ok_or_else(|| format!("Invalid timestamp: {value}"))
+    } else {
+        DateTime::parse_from_rfc3339(value)
+            .map(|datetime| datetime.with_timezone(&Utc))
+            .map_err(|err| format!("Invalid timestamp {value}: {err}"))
+    }
+}
+
+fn deserialize_timestamp_param<'de, D>(deserializer: D) -> Result<DateTime<Utc>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let value = String::deserialize(deserializer)?;
+    parse_timestamp_param(&value).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_optional_timestamp_param<'de, D>(
+    deserializer: D,
+) -> Result<Option<DateTime<Utc>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|value| parse_timestamp_param(&value).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct NameHistoryPath {
+    pub user_id: String,
+}
+
+#[derive(Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PreviousName {
+    pub user_login: String,
+    pub display_name: String,
+    #[schemars(with = "String")]
+    pub first_timestamp: DateTime<Utc>,
+    #[schemars(with = "String")]
+    pub last_timestamp: DateTime<Utc>,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct UserStatsParams {
+    #[serde(flatten)]
+    pub user: UserParam,
+}
+
+#[derive(Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct UserStats {
+    #[serde(rename = "userID")]
+    pub user_id: String,
+    /// Total amount of chat messages
+    pub messages: u64,
+    /// How many times the user has been timed out
+    pub timeouts: u64,
+    /// How many times the user has been banned
+    pub bans: u64,
+    /// When the user first sent a message or user notice, timeouts and bans are not included
+    #[schemars(with = "Option<String>")]
+    pub first_seen: Option<DateTime<Utc>>,
+    #[schemars(with = "Option<String>")]
+    pub last_seen: Option<DateTime<Utc>>,
+    pub channels: Vec<UserChannelStats>,
+    pub months: Vec<MonthlyMessageCount>,
+}
+
+impl UserStats {
+    /// Sums up the statistics of all channels, `None` if the user has no logs in any channel
+    pub fn new(
+        user_id: String,
+        channels: Vec<UserChannelStats>,
+        months: Vec<MonthlyMessageCount>,
+    ) -> Option<Self> {
+        if channels.is_empty() {
+            return None;
+        }
+
+        let first_seen = channels
+            .iter()
+            .filter_map(|channel| channel.first_seen)
+            .min();
+        let last_seen = channels
+            .iter()
+            .filter_map(|channel| channel.last_seen)
+            .max();
+
+        Some(Self {
+            user_id,
+            messages: channels.iter().map(|channel| channel.messages).sum(),
+            timeouts: channels.iter().map(|channel| channel.timeouts).sum(),
+            bans: channels.iter().map(|channel| channel.bans).sum(),
+            first_seen,
+            last_seen,
+            channels,
+            months,
+        })
+    }
+}
+
+#[derive(Serialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct UserChannelStats {
+    #[serde(rename = "channelID")]
+    pub channel_id: String,
+    pub messages: u64,
+    pub timeouts: u64,
+    pub bans: u64,
+    /// When the user first sent a message or user notice, timeouts and bans are not included
+    #[schemars(with = "Option<String>")]
+    pub first_seen: Option<DateTime<Utc>>,
+    #[schemars(with = "Option<String>")]
+    pub last_seen: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Deserialize, Row, JsonSchema)]
+pub struct MonthlyMessageCount {
+    pub year: u16,
+    pub month: u8,
+    pub messages: u64,
+}
+
+#[derive(Deserialize, JsonSchema)]
+pub struct ChannelStatsParams {
+    /// Start of the period (RFC3339 or unix milliseconds). Defaults to 30 days before `to`
+    #[serde(default, deserialize_with = "deserialize_optional_timestamp_param")]
+    #[schemars(with = "Option<String>")]
+    pub from: Option<DateTime<Utc>>,
+    /// End of the period (RFC3339 or unix milliseconds). Defaults to the current time
+    #[serde(default, deserialize_with = "deserialize_optional_timestamp_param")]
+    #[schemars(with = "Option<String>")]
+    pub to: Option<DateTime<Utc>>,
+    /// How many chatters to return in the leaderboard. Defaults to 10, maximum is 100
+    pub limit: Option<u64>,
+}
+
+impl ChannelStatsParams {
+    pub fn period(&self) -> Result<(DateTime<Utc>, DateTime<Utc>), Error> {
+        let to = self.to.unwrap_or_else(Utc::now);
+        let from = self
+            .from
+            .unwrap_or_else(|| to - Duration::days(DEFAULT_STATS_PERIOD_DAYS));
+
+        if from > to {
+            return Err(Error::InvalidParam(
+                "`from` must be earlier than `to`".to_owned(),
+            ));
+        }
+
+        Ok((from, to))
+    }
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct ChannelStats {
+    pub days: Vec<ChannelDayStats>,
+}
+
+#[derive(Serialize, Deserialize, Row, JsonSchema)]
+pub struct ChannelDayStats {
+    /// Date in the `YYYY-MM-DD` format
+    pub day: String,
+    pub messages: u64,
+    /// Number of unique chatters
+    pub chatters: u64,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct TopChatters {
+    pub chatters: Vec<TopChatter>,
+}
+
+#[derive(Serialize, JsonSchema)]
+pub struct TopChatter {
+    #[serde(rename = "userID")]
+    pub user_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub messages: u64,
+}
+
+#[derive(Serialize, Deserialize, Row, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct MutationProgress {
+    pub table: String,
+    #[serde(rename = "mutationID")]
+    pub mutation_id: String,
+    pub command: String,
+    /// Number of data parts which still have to be rewritten
+    pub parts_to_do: i64,
+    /// Empty unless the mutation has failed to apply
+    pub latest_fail_reason: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_timestamp_param, LogsRangeParams, UserChannelStats, UserStats};
+    use chrono::{Duration, TimeZone, Utc};
+    use pretty_assertions::assert_eq;
+
+    #[test]
+    fn parse_unix_millis_timestamp() {
+        assert_eq!(
+            parse_timestamp_param("1642720582342"),
+            Ok(Utc.timestamp_millis_opt(1642720582342).unwrap())
+        );
+    }
+
+    #[test]
+    fn parse_rfc3339_timestamp() {
+        assert_eq!(
+            parse_timestamp_param("2022-01-20T23:16:22Z"),
+            Ok(Utc.with_ymd_and_hms(2022, 1, 20, 23, 16, 22).unwrap())
+        );
+        assert_eq!(
+            parse_timestamp_param("2022-01-21T01:16:22+02:00"),
+            Ok(Utc.with_ymd_and_hms(2022, 1, 20, 23, 16, 22).unwrap())
+        );
+    }
+
+    #[test]
+    fn reject_invalid_timestamps() {
+        assert!(parse_timestamp_param("").is_err());
+        assert!(parse_timestamp_param("2022-01-20").is_err());
+        assert!(parse_timestamp_param("yesterday").is_err());
+        assert!(parse_timestamp_param(&i64::MAX.to_string()).is_err());
+    }
+
+    #[test]
+    fn limit_range_length() {
+        let from = Utc.with_ymd_and_hms(2022, 1, 1, 0, 0, 0).unwrap();
+        let params = LogsRangeParams {
+            from,
+            to: Some(from + Duration::days(365)),
+        };
+        assert_eq!(params.range().unwrap(), (from, from + Duration::days(31)));
+
+        let params = LogsRangeParams {
+            from,
+            to: Some(from + Duration::hours(1)),
+        };
+        assert_eq!(params.range().unwrap(), (from, from + Duration::hours(1)));
+
+        let params = LogsRangeParams {
+            from,
+            to: Some(from),
+        };
+        assert!(params.range().is_err());
+    }
+
+    fn channel_stats(
+        channel_id: &str,
+        messages: u64,
+        first_day: u32,
+        last_day: u32,
+    ) -> UserChannelStats {
+        UserChannelStats {
+            channel_id: channel_id.to_owned(),
+            messages,
+            timeouts: 1,
+            bans: 0,
+            first_seen: Some(Utc.with_ymd_and_hms(2022, 1, first_day, 0, 0, 0).unwrap()),
+            last_seen: Some(Utc.with_ymd_and_hms(2022, 1, last_day, 0, 0, 0).unwrap()),
+        }
+    }
+
+    #[test]
+    fn sum_user_stats_of_all_channels() {
+        let stats = UserStats::new(
+            "1".to_owned(),
+            vec![channel_stats("2", 10, 5, 20), channel_stats("3", 5, 1, 10)],
+            vec![],
+        )
+        .unwrap();
+
+        assert_eq!(stats.messages, 15);
+        assert_eq!(stats.timeouts, 2);
+        assert_eq!(stats.bans, 0);
+        assert_eq!(
+            stats.first_seen,
+            Some(Utc.with_ymd_and_hms(2022, 1, 1, 0, 0, 0).unwrap())
+        );
+        assert_eq!(
+            stats.last_seen,
+            Some(Utc.with_ymd_and_hms(2022, 1, 20, 0, 0, 0).unwrap())
+        );
+        assert_eq!(stats.channels.len(), 2);
+    }
+
+    #[test]
+    fn timeouts_are_not_seen() {
+        let mut timed_out = channel_stats("3", 0, 1, 1);
+        timed_out.first_seen = None;
+        timed_out.last_seen = None;
+
+        let stats = UserStats::new(
+            "1".to_owned(),
+            vec![channel_stats("2", 10, 5, 20), timed_out],
+            vec![],
+        )
+        .unwrap();
+
+        assert_eq!(
+            stats.first_seen,
+            Some(Utc.with_ymd_and_hms(2022, 1, 5, 0, 0, 0).unwrap())
+        );
+        assert_eq!(stats.timeouts, 2);
+    }
+
+    #[test]
+    fn no_user_stats_without_channels() {
+        assert!(UserStats::new("1".to_owned(), vec![], vec![]).is_none());
+    }
+}
@@ -4,7 +4,7 @@ use axum::{
     response::Response,
 };
 use std::time::Duration;
-use tracing::{info, info_span, Span};
+use tracing::{field, info, info_span, Span};
 
 pub fn make_span_with(request: &Request<Body>) -> Span {
     let method = request.method().to_string();
@@ -13,7 +13,8 @@ pub fn make_span_with(request: &Request<Body>) -> Span {
     info_span!(
         "http-request",
         "http.method" = method.as_str(),
-        "http.uri" = url.as_str()
+        "http.uri" = url.as_str(),
+        "admin.key" = field::Empty
     )
 }
 